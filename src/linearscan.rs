@@ -6,6 +6,9 @@ mod allocator;
 #[path="linearscan/api.rs"]
 mod api;
 
+#[path="linearscan/dominance.rs"]
+mod dominance;
+
 #[path="linearscan/flatten.rs"]
 mod flatten;
 
@@ -23,3 +26,6 @@ mod json;
 
 #[path="linearscan/liveness.rs"]
 mod liveness;
+
+#[path="linearscan/validate.rs"]
+mod validate;
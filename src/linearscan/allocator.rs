@@ -1,17 +1,39 @@
 use extra::sort::quick_sort;
 use extra::smallintmap::SmallIntMap;
-use std::{vec, uint, iterator};
+use extra::bitv::BitvSet;
+use std::{vec, uint, util, iterator};
 use linearscan::{KindHelper, RegisterHelper, GroupHelper};
-use linearscan::graph::{Graph, Interval,
-                        IntervalId, InstrId, StackId, BlockId,
-                        UseAny, UseRegister, UseFixed,
-                        Value, RegisterVal, StackVal};
+use linearscan::graph::{Graph, Interval, LiveRange,
+                        IntervalId, InstrId, StackId, BlockId, Gap, Phi, ToPhi,
+                        Proj, Param, Move, Swap, Remat,
+                        UseAny, UseRegister, UseFixed, UseStack, UseFixedStack,
+                        UseSameAs, UseAtEnd,
+                        Value, VirtualVal, RegisterVal, StackVal};
 use linearscan::flatten::Flatten;
 use linearscan::liveness::Liveness;
 use linearscan::gap::GapResolver;
+use linearscan::dominance::Dominance;
+
+pub struct AllocatorResult<R> {
+  // Count of stack slots in every group, i.e. the highest slot id used plus
+  // one. Spill slots are skipping ids of fixed stack uses, and these are
+  // counted too
+  spill_count: ~[uint],
+  // Registers used by allocated intervals in every group
+  used_registers: ~[~[R]],
+  // Blocks and instructions that were unreachable from root
+  removed_blocks: ~[BlockId],
+  removed_instructions: ~[InstrId],
+  // New ids of blocks and instructions renumbered by this allocation,
+  // indexed by ids they had before it. Ids that aren't present are unchanged
+  renamed_blocks: ~SmallIntMap<BlockId>,
+  renamed_instructions: ~SmallIntMap<InstrId>
+}
 
-pub struct AllocatorResult {
-  spill_count: ~[uint]
+pub struct AllocatorConfig {
+  // Spill all split children of the interval into the same stack slot, and
+  // store the value there only once
+  shared_spill_slot: bool
 }
 
 struct GroupResult {
@@ -23,17 +45,31 @@ struct AllocatorState<G, R> {
   register_count: uint,
   spill_count: uint,
   spills: ~[Value<G, R>],
+  // Stack slots required by fixed stack uses, never used for spills
+  fixed_spills: ~BitvSet,
+  // Positions of instructions clobbering group's registers
+  calls: ~[InstrId],
   unhandled: ~[IntervalId],
   active: ~[IntervalId],
   inactive: ~[IntervalId]
 }
 
-pub trait Allocator {
+pub trait Allocator<R> {
   // Prepare for allocation
   fn prepare(&mut self);
 
   // Allocate registers
-  fn allocate(&mut self) -> Result<AllocatorResult, ~str>;
+  fn allocate(&mut self) -> Result<AllocatorResult<R>, ~str>;
+
+  // Allocate registers using specified configuration
+  fn allocate_with(&mut self,
+                   config: AllocatorConfig) -> Result<AllocatorResult<R>, ~str>;
+
+  // Drop results of previous allocation, so graph could be modified
+  // and allocated again. NOTE: blocks and instructions keep ids assigned by
+  // allocation (see `renamed_*` in its result), and blocks inserted on
+  // critical edges stay in the graph
+  fn reset_allocation(&mut self);
 }
 
 enum SplitConf {
@@ -44,6 +80,11 @@ enum SplitConf {
 trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
   // Walk unhandled intervals in the order of increasing starting point
   fn walk_intervals(&mut self, group: &G) -> Result<GroupResult, ~str>;
+  // Create fixed intervals blocking group's registers that are aliasing
+  // registers occupied by other groups. Groups allocated earlier block
+  // their actual registers, groups allocated later - registers of their
+  // fixed uses and clobbers, and are blocked by this group in turn
+  fn alias_blockers(&mut self, group: &G) -> ~[IntervalId];
   // Try allocating free register
   fn allocate_free_reg<'r>(&'r mut self,
                            current: IntervalId,
@@ -53,13 +94,29 @@ trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
                               current: IntervalId,
                               state: &'r mut AllocatorState<G, R>)
       -> Result<(), ~str>;
+  // Reassign spill slots of group's intervals, return the highest used slot
+  // id plus one
+  fn color_spills(&mut self, group: &G, config: &AllocatorConfig) -> uint;
+
   // Add movements on block edges
   fn resolve_data_flow(&mut self, list: &[BlockId]);
 
+  // Add parallel movements for runs of ToPhi instructions
+  fn resolve_to_phi(&mut self, list: &[BlockId]);
+
+  // Copy inputs of two-address instructions into their outputs
+  fn resolve_same_as(&mut self, list: &[BlockId]);
+
+  // Replace loads of rematerializable values with their recomputation
+  fn resolve_remat(&mut self);
+
+  // Remove stores into the slot that already holds the value
+  fn eliminate_stores(&mut self);
+
   // Build live ranges for each interval
   fn build_ranges(&mut self, blocks: &[BlockId]) -> Result<(), ~str>;
 
-  // Split intervals with fixed uses
+  // Split intervals with fixed and stack uses
   fn split_fixed(&mut self);
 
   //
@@ -72,6 +129,14 @@ trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
   // Get register hint if present
   fn get_hint(&mut self, current: IntervalId) -> Option<R>;
 
+  // Find defining instructions of rematerializable intervals, that are never
+  // read from the stack
+  fn remat_defs(&self) -> SmallIntMap<InstrId>;
+
+  // Get defining instruction of spilled rematerializable interval
+  fn remat_def(&self, defs: &SmallIntMap<InstrId>, id: &IntervalId)
+      -> Option<InstrId>;
+
   // Split interval at some optimal position and add split child to unhandled
   fn split<'r>(&'r mut self,
                current: IntervalId,
@@ -105,7 +170,7 @@ trait AllocatorHelper<G: GroupHelper<R>, R: RegisterHelper<G> > {
 
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
-     K: KindHelper<G, R> > Allocator for Graph<K, G, R> {
+     K: KindHelper<G, R> > Allocator<R> for Graph<K, G, R> {
   fn prepare(&mut self) {
     if self.prepared {
       return;
@@ -120,7 +185,83 @@ impl<G: GroupHelper<R>,
     self.prepared = true;
   }
 
-  fn allocate(&mut self) -> Result<AllocatorResult, ~str> {
+  fn reset_allocation(&mut self) {
+    if !self.prepared {
+      return;
+    }
+
+    // Remove gaps, flatten will insert them again
+    let mut gaps = ~[];
+    for (&id, instr) in self.instructions.iter() {
+      match instr.kind {
+        Gap => gaps.push(id),
+        _ => ()
+      }
+    }
+    for id in gaps.iter() {
+      self.instructions.pop(id);
+    }
+
+    // Reset blocks to their initial state
+    for id in self.get_block_list().iter() {
+      let instructions = do self.get_block(id).instructions.filtered |instr| {
+        self.instructions.contains_key(&instr.to_uint())
+      };
+      let block = self.get_mut_block(id);
+      block.instructions = instructions;
+      block.loop_index = 0;
+      block.loop_depth = 0;
+      block.incoming_forward_branches = block.predecessors.len();
+      block.live_gen = ~BitvSet::new();
+      block.live_kill = ~BitvSet::new();
+      block.live_in = ~BitvSet::new();
+      block.live_out = ~BitvSet::new();
+    }
+
+    // Keep only instructions' intervals, remove split children and
+    // physical intervals
+    let mut owned = ~BitvSet::new();
+    for (&id, instr) in self.instructions.iter() {
+      for output in self.get_outputs(&InstrId(id)).iter() {
+        owned.insert(output.to_uint());
+      }
+      for tmp in instr.temporary.iter() {
+        owned.insert(tmp.to_uint());
+      }
+    }
+
+    let mut intervals = ~[];
+    for (&id, _) in self.intervals.iter() {
+      intervals.push(id);
+    }
+    for id in intervals.iter() {
+      if !owned.contains(id) {
+        self.intervals.pop(id);
+        loop;
+      }
+
+      let interval = self.get_mut_interval(&IntervalId(*id));
+      interval.value = VirtualVal(interval.value.group());
+      interval.ranges = ~[];
+      interval.uses = ~[];
+      interval.children = ~[];
+    }
+
+    self.gaps = ~SmallIntMap::new();
+    self.physical = ~SmallIntMap::new();
+    self.removed_blocks = ~[];
+    self.removed_instructions = ~[];
+    self.renamed_blocks = ~SmallIntMap::new();
+    self.renamed_instructions = ~SmallIntMap::new();
+    self.prepared = false;
+  }
+
+  fn allocate(&mut self) -> Result<AllocatorResult<R>, ~str> {
+    return self.allocate_with(AllocatorConfig { shared_spill_slot: false });
+  }
+
+  fn allocate_with(&mut self, config: AllocatorConfig)
+      -> Result<AllocatorResult<R>, ~str> {
     self.prepare();
 
     // Create physical fixed intervals
@@ -148,6 +289,10 @@ impl<G: GroupHelper<R>,
           // Walk intervals!
           match self.walk_intervals(group) {
             Ok(res) => {
+              // Walk reuses spill slots only approximately, pack them using
+              // liveness of the spilled intervals
+              let mut res = res;
+              res.spill_count = self.color_spills(group, &config);
               results.push(res);
             },
             Err(reason) => { return Err(reason); }
@@ -157,6 +302,20 @@ impl<G: GroupHelper<R>,
         // Add moves between blocks
         self.resolve_data_flow(list);
 
+        // Add moves for ToPhis
+        self.resolve_to_phi(list);
+
+        // Add moves for two-address instructions
+        self.resolve_same_as(list);
+
+        // Recompute cheap values instead of spilling them
+        self.resolve_remat();
+
+        // Store shared spill slots only once
+        if config.shared_spill_slot {
+          self.eliminate_stores();
+        }
+
         // Resolve parallel moves
         self.resolve_gaps();
 
@@ -167,7 +326,14 @@ impl<G: GroupHelper<R>,
         return Ok(AllocatorResult {
           spill_count: do results.map() |result| {
             result.spill_count
-          }
+          },
+          used_registers: self.used_registers(),
+          removed_blocks: self.removed_blocks.clone(),
+          removed_instructions: self.removed_instructions.clone(),
+          renamed_blocks: util::replace(&mut self.renamed_blocks,
+                                        ~SmallIntMap::new()),
+          renamed_instructions: util::replace(&mut self.renamed_instructions,
+                                              ~SmallIntMap::new())
         });
       },
       Err(reason) => { return Err(reason); }
@@ -187,11 +353,32 @@ impl<G: GroupHelper<R>,
       register_count: reg_count,
       spill_count: 0,
       spills: ~[],
+      fixed_spills: ~BitvSet::new(),
+      calls: ~[],
       unhandled: ~[],
       active: ~[],
       inactive: ~[]
     };
 
+    for (&id, _) in self.instructions.iter() {
+      if self.clobbers(group, &InstrId(id)) {
+        state.calls.push(InstrId(id));
+      }
+    }
+    for (_, interval) in self.intervals.iter() {
+      for u in interval.uses.iter() {
+        match u.kind {
+          UseFixedStack(ref g, slot) if g == group => {
+            state.fixed_spills.insert(slot.to_uint());
+          },
+          _ => ()
+        }
+      }
+    }
+
+    // Other groups' registers may share storage with this group's ones
+    let blockers = self.alias_blockers(group);
+
     // We'll work with intervals that contain any ranges
     for (_, interval) in self.intervals.iter() {
       if &interval.value.group() == state.group && interval.ranges.len() > 0 {
@@ -242,13 +429,24 @@ impl<G: GroupHelper<R>,
 
       // Skip non-virtual intervals
       if self.get_interval(&current).value.is_virtual() {
-        // Allocate free register
-        if !self.allocate_free_reg(current, state) {
-          // Or spill some active register
-          match self.allocate_blocked_reg(current, state) {
-            Ok(_) => (),
-            Err(err) => {
-              return Err(err);
+        let stack_use = self.get_interval(&current).stack_use();
+        match stack_use {
+          // Intervals with stack uses should be spilled
+          Some(u) => {
+            self.get_mut_interval(&current).value = match u.kind {
+              UseFixedStack(g, slot) => StackVal(g, slot),
+              _ => state.get_spill()
+            };
+          },
+
+          // Allocate free register
+          None => if !self.allocate_free_reg(current, state) {
+            // Or spill some active register
+            match self.allocate_blocked_reg(current, state) {
+              Ok(_) => (),
+              Err(err) => {
+                return Err(err);
+              }
             }
           }
         }
@@ -261,9 +459,80 @@ impl<G: GroupHelper<R>,
       }
     }
 
+    // Blockers are needed only during the walk
+    for id in blockers.iter() {
+      self.intervals.pop(&id.to_uint());
+    }
+
+    // Fixed stack slot can't hold two values at once
+    let mut fixed = ~[];
+    for (_, interval) in self.intervals.iter() {
+      if &interval.value.group() != group || interval.ranges.len() == 0 {
+        loop;
+      }
+      match interval.stack_use() {
+        Some(u) => match u.kind {
+          UseFixedStack(_, slot) => fixed.push((interval.id, slot)),
+          _ => ()
+        },
+        None => ()
+      }
+    }
+    for (i, &(a, slot_a)) in fixed.iter().enumerate() {
+      for &(b, slot_b) in fixed.slice_from(i + 1).iter() {
+        if slot_a == slot_b && self.get_intersection(&a, &b).is_some() {
+          return Err(~"Fixed stack slot is used by two values at once");
+        }
+      }
+    }
+
     return Ok(GroupResult { spill_count: state.spill_count });
   }
 
+  fn alias_blockers(&mut self, group: &G) -> ~[IntervalId] {
+    // Registers occupied by other groups and ranges of their occupation
+    let mut occupied = ~[];
+    for (_, interval) in self.intervals.iter() {
+      if &interval.value.group() == group || interval.ranges.len() == 0 {
+        loop;
+      }
+      match interval.value {
+        // Allocated and physical intervals
+        RegisterVal(ref r) => {
+          occupied.push((r.clone(), interval.ranges.clone()));
+        },
+        // Intervals of groups that aren't allocated yet, only fixed uses
+        // are known to need registers
+        VirtualVal(_) => for u in interval.uses.iter() {
+          match u.kind {
+            UseFixed(ref r) => {
+              occupied.push((r.clone(),
+                             ~[LiveRange { start: u.pos, end: u.pos.next() }]));
+            },
+            _ => ()
+          }
+        },
+        _ => ()
+      }
+    }
+
+    let mut result = ~[];
+    for &(ref reg, ref ranges) in occupied.iter() {
+      for alias in reg.aliases().iter() {
+        if &alias.group() != group {
+          loop;
+        }
+        let id = Interval::<G, R>::new::<K>(self, group.clone());
+        let blocker = self.get_mut_interval(&id);
+        blocker.value = RegisterVal(alias.clone());
+        blocker.fixed = true;
+        blocker.ranges = ranges.clone();
+        result.push(id);
+      }
+    }
+    return result;
+  }
+
   fn allocate_free_reg<'r>(&'r mut self,
                            current: IntervalId,
                            state: &'r mut AllocatorState<G, R>) -> bool {
@@ -282,7 +551,7 @@ impl<G: GroupHelper<R>,
       }
     }
 
-    // Choose register with maximum free_pos
+    // Choose register
     let mut reg = 0;
     let mut max_pos = InstrId(0);
     match self.get_interval(&current).next_fixed_use(InstrId(0)) {
@@ -297,21 +566,51 @@ impl<G: GroupHelper<R>,
         }
       },
 
-      // Other intervals should prefer register that's free for a longer time
+      // Other intervals should prefer the cheapest register that's free for
+      // the whole lifetime, or the one that's free for a longer time
       None => {
-        // Prefer hinted register
-        match hint {
-          Some(hint) => for (i, &pos) in free_pos.iter().enumerate() {
-            if pos > max_pos.to_uint() ||
-               hint.to_uint() == i && pos == max_pos.to_uint() {
-              max_pos = InstrId(pos);
-              reg = i;
-            }
-          },
-          None => for (i, &pos) in free_pos.iter().enumerate() {
-            if pos > max_pos.to_uint() {
-              max_pos = InstrId(pos);
-              reg = i;
+        let end = self.get_interval(&current).end();
+        let crosses_call = do state.calls.any() |pos| {
+          self.get_interval(&current).covers(*pos)
+        };
+
+        // Hinted register saves a move, callee-saved registers are cheaper
+        // for intervals living across calls (as they're not spilled), and
+        // caller-saved are cheaper for the rest (as they're not saved in
+        // prologue)
+        let mut best_cost = uint::max_value;
+        for (i, &pos) in free_pos.iter().enumerate() {
+          if pos < end.to_uint() {
+            loop;
+          }
+          let r: R = RegisterHelper::from_uint(state.group, i);
+          let cost = match hint {
+            Some(ref hint) if hint.to_uint() == i => 0,
+            _ if r.callee_saved() == crosses_call => 1,
+            _ => 2
+          };
+          if cost < best_cost {
+            best_cost = cost;
+            max_pos = InstrId(pos);
+            reg = i;
+          }
+        }
+
+        // No such register, prefer hinted one of those free for longer time
+        if best_cost == uint::max_value {
+          match hint {
+            Some(hint) => for (i, &pos) in free_pos.iter().enumerate() {
+              if pos > max_pos.to_uint() ||
+                 hint.to_uint() == i && pos == max_pos.to_uint() {
+                max_pos = InstrId(pos);
+                reg = i;
+              }
+            },
+            None => for (i, &pos) in free_pos.iter().enumerate() {
+              if pos > max_pos.to_uint() {
+                max_pos = InstrId(pos);
+                reg = i;
+              }
             }
           }
         }
@@ -549,6 +848,43 @@ impl<G: GroupHelper<R>,
     }
   }
 
+  fn remat_defs(&self) -> SmallIntMap<InstrId> {
+    let mut defs = SmallIntMap::new();
+    for (&id, _) in self.instructions.iter() {
+      let instr_id = InstrId(id);
+      if !self.is_rematerializable(&instr_id) {
+        loop;
+      }
+
+      // Values with stack uses are reading their spill slots
+      let output = self.get_output(&instr_id);
+      let mut stack_use = self.get_interval(&output).stack_use().is_some();
+      for child in self.get_interval(&output).children.iter() {
+        stack_use = stack_use ||
+                    self.get_interval(child).stack_use().is_some();
+      }
+      if !stack_use {
+        defs.insert(output.to_uint(), instr_id);
+      }
+    }
+    return defs;
+  }
+
+  fn remat_def(&self, defs: &SmallIntMap<InstrId>, id: &IntervalId)
+      -> Option<InstrId> {
+    let parent = match self.get_interval(id).parent {
+      Some(p) => p,
+      None => *id
+    };
+    match self.get_interval(id).value {
+      StackVal(_, _) => match defs.find(&parent.to_uint()) {
+        Some(def) => Some(*def),
+        None => None
+      },
+      _ => None
+    }
+  }
+
   fn split<'r>(&'r mut self,
                current: IntervalId,
                conf: SplitConf,
@@ -615,6 +951,113 @@ impl<G: GroupHelper<R>,
     };
   }
 
+  fn color_spills(&mut self, group: &G, config: &AllocatorConfig) -> uint {
+    // Spilled children of rematerializable values are recomputed on every
+    // load, and don't need a slot
+    let remat = self.remat_defs();
+
+    // Slots of fixed stack uses are kept as is
+    let mut count = 0;
+    let mut fixed = BitvSet::new();
+
+    // Intervals sharing the slot, and intervals during which it is occupied
+    let mut members: ~[~[IntervalId]] = ~[];
+    let mut lifetimes: ~[~[IntervalId]] = ~[];
+    let mut parents = SmallIntMap::new();
+    for (_, interval) in self.intervals.iter() {
+      if &interval.value.group() != group || interval.ranges.len() == 0 {
+        loop;
+      }
+      match interval.value {
+        StackVal(_, slot) => {
+          let is_fixed = match interval.stack_use() {
+            Some(u) => match u.kind {
+              UseFixedStack(_, _) => true,
+              _ => false
+            },
+            None => false
+          };
+          if is_fixed {
+            fixed.insert(slot.to_uint());
+            count = uint::max(count, slot.to_uint() + 1);
+            loop;
+          }
+          // Definition itself might still write into the slot
+          if interval.parent.is_some() &&
+             self.remat_def(&remat, &interval.id).is_some() {
+            loop;
+          }
+        },
+        _ => loop
+      }
+
+      if !config.shared_spill_slot {
+        members.push(~[interval.id]);
+        lifetimes.push(~[interval.id]);
+        loop;
+      }
+
+      // Shared slot holds the value during its whole lifetime
+      let parent = match interval.parent {
+        Some(p) => p,
+        None => interval.id
+      };
+      match parents.find(&parent.to_uint()) {
+        Some(&i) => {
+          members[i].push(interval.id);
+          loop;
+        },
+        None => ()
+      }
+      let mut family = ~[parent];
+      family.push_all(self.get_interval(&parent).children);
+      parents.insert(parent.to_uint(), members.len());
+      members.push(~[interval.id]);
+      lifetimes.push(family);
+    }
+
+    let mut order = ~[];
+    for (i, _) in lifetimes.iter().enumerate() {
+      order.push(i);
+    }
+    do quick_sort(order) |left, right| {
+      let lstart = self.get_interval(&lifetimes[*left][0]).start();
+      let rstart = self.get_interval(&lifetimes[*right][0]).start();
+
+      lstart <= rstart
+    };
+
+    // Put each interval into the first slot, that isn't occupied during its
+    // lifetime
+    let mut slots: ~[~[IntervalId]] = ~[];
+    for &i in order.iter() {
+      let mut slot = 0;
+      loop {
+        let busy = fixed.contains(&slot) || (slot < slots.len() &&
+            slots[slot].any(|other| {
+              lifetimes[i].any(|id| {
+                self.get_intersection(id, other).is_some()
+              })
+            }));
+        if !busy {
+          break;
+        }
+        slot += 1;
+      }
+      while slots.len() <= slot {
+        slots.push(~[]);
+      }
+      slots[slot].push_all(lifetimes[i]);
+      count = uint::max(count, slot + 1);
+      for id in members[i].iter() {
+        self.get_mut_interval(id).value = StackVal(group.clone(),
+                                                   StackId(slot));
+      }
+    }
+
+    return count;
+  }
+
   fn resolve_data_flow(&mut self, list: &[BlockId]) {
     for block_id in list.iter() {
       let block_end = self.get_block(block_id).end().prev();
@@ -622,6 +1065,11 @@ impl<G: GroupHelper<R>,
       for succ_id in successors.iter() {
         let succ_start = self.get_block(succ_id).start().clone();
         let live_in = self.get_block(succ_id).live_in.clone();
+        let gap_pos = if successors.len() > 1 {
+          succ_start
+        } else {
+          block_end
+        };
 
         for interval in live_in.iter() {
           let interval_id = IntervalId(interval);
@@ -635,15 +1083,224 @@ impl<G: GroupHelper<R>,
           let to = self.child_at(&parent, succ_start)
                        .expect("Interval should exist at succ start");
           if from != to {
-            let gap_pos = if successors.len() == 2 {
-              succ_start
-            } else {
-              block_end
-            };
             self.get_mut_gap(&gap_pos).add_move(&from, &to);
           }
         }
+
+        // Move inputs into successor's phis
+        let phi_moves = self.get_phi_moves(block_id, succ_id);
+        for &(input, phi) in phi_moves.iter() {
+          let from = self.child_at(&input, block_end)
+                         .expect("Phi input should exist at pred end");
+          let to = self.child_at(&phi, succ_start)
+                       .expect("Phi should exist at succ start");
+          if from != to {
+            self.get_mut_gap(&gap_pos).add_move(&from, &to);
+          }
+        }
+      }
+    }
+  }
+
+  fn resolve_to_phi(&mut self, list: &[BlockId]) {
+    for block_id in list.iter() {
+      let instructions = self.get_block(block_id).instructions.clone();
+      for instr_id in instructions.iter() {
+        match self.get_instr(instr_id).kind {
+          ToPhi(_) => (),
+          _ => loop
+        };
+
+        // Inputs are read right before the start of the run, and outputs are
+        // written at its start
+        let start = self.to_phi_run_start(instr_id);
+        let input = self.get_output(&self.get_instr(instr_id).inputs[0]);
+        let output = self.get_output(instr_id);
+        let from = self.child_at(&input, start.prev())
+                       .expect("ToPhi input should exist before run");
+        let to = self.child_at(&output, start)
+                     .expect("ToPhi output should exist at run start");
+        if from != to {
+          self.get_mut_gap(&start).add_move(&from, &to);
+        }
+      }
+    }
+  }
+
+  fn resolve_same_as(&mut self, list: &[BlockId]) {
+    for block_id in list.iter() {
+      let instructions = self.get_block(block_id).instructions.clone();
+      for instr_id in instructions.iter() {
+        let out_kinds = self.get_instr(instr_id).kind.result_kinds();
+        let outputs = self.get_outputs(instr_id);
+        for (i, kind) in out_kinds.iter().enumerate() {
+          let input = match kind.same_as() {
+            Some(input) => self.get_instr(instr_id).inputs[input],
+            None => loop
+          };
+
+          // Input is copied into output in the gap before instruction,
+          // NOTE: input may be defined by the call right at this gap
+          let gap = instr_id.prev();
+          let input = self.get_output(&input);
+          let from = match self.child_at(&input, gap.prev()) {
+            Some(child) => child,
+            None => self.child_at(&input, gap)
+                        .expect("Input should exist at gap")
+          };
+          let to = self.child_at(&outputs[i], gap)
+                       .expect("Output should exist at gap");
+          if from != to {
+            self.get_mut_gap(&gap).add_move(&from, &to);
+          }
+        }
+      }
+    }
+  }
+
+  fn resolve_remat(&mut self) {
+    let defs = self.remat_defs();
+
+    let mut keys = ~[];
+    for (id, _) in self.gaps.iter() {
+      keys.push(*id);
+    }
+    for id in keys.iter() {
+      let actions = self.gaps.find(id).unwrap().actions.clone();
+      let mut result = ~[];
+      for action in actions.iter() {
+        let from = self.remat_def(&defs, &action.from);
+        let to = self.remat_def(&defs, &action.to);
+
+        if to.is_some() {
+          // Value is never loaded from this spill slot, store is useless
+          loop;
+        }
+
+        match from {
+          Some(def) => {
+            let mut action = action.clone();
+            action.kind = Remat(def);
+            result.push(action);
+          },
+          None => result.push(action.clone())
+        }
       }
+      self.gaps.find_mut(id).unwrap().actions = result;
+    }
+  }
+
+  fn eliminate_stores(&mut self) {
+    let tree = self.dominators();
+
+    // Parents defined more than once (phis, or receiving other values in gap
+    // moves) might hold a different value in the slot
+    let mut multiple = BitvSet::new();
+    for (_, instr) in self.instructions.iter() {
+      match instr.kind {
+        Phi(_) | ToPhi(_) => {
+          multiple.insert(instr.output.unwrap().to_uint());
+        },
+        _ => ()
+      }
+    }
+    for (_, state) in self.gaps.iter() {
+      for action in state.actions.iter() {
+        let from = self.get_interval(&action.from);
+        let to = self.get_interval(&action.to);
+        let from_parent = match from.parent {
+          Some(p) => p,
+          None => from.id
+        };
+        let to_parent = match to.parent {
+          Some(p) => p,
+          None => to.id
+        };
+        if from_parent != to_parent {
+          multiple.insert(to_parent.to_uint());
+          if action.kind == Swap {
+            multiple.insert(from_parent.to_uint());
+          }
+        }
+      }
+    }
+
+    // Positions where slots are written, with the values they are receiving
+    let mut stores = ~[];
+    for (_, interval) in self.intervals.iter() {
+      match interval.value {
+        // Spilled values are written by their definitions
+        StackVal(_, _) if interval.parent.is_none() &&
+                          interval.ranges.len() != 0 => {
+          stores.push((interval.start(), interval.id, interval.value.clone()));
+        },
+        _ => ()
+      }
+    }
+    for (&id, state) in self.gaps.iter() {
+      for action in state.actions.iter() {
+        let to = self.get_interval(&action.to);
+        match to.value {
+          StackVal(_, _) => {
+            let parent = match to.parent {
+              Some(p) => p,
+              None => to.id
+            };
+            stores.push((InstrId(id), parent, to.value.clone()));
+          },
+          _ => ()
+        }
+      }
+    }
+
+    let mut keys = ~[];
+    for (&id, _) in self.gaps.iter() {
+      keys.push(id);
+    }
+    for id in keys.iter() {
+      let pos = InstrId(*id);
+      let block = self.get_instr(&pos).block;
+      let actions = self.gaps.find(id).unwrap().actions.clone();
+      let mut result = ~[];
+      for action in actions.iter() {
+        let from = self.get_interval(&action.from);
+        let to = self.get_interval(&action.to);
+        if action.kind != Move {
+          result.push(action.clone());
+          loop;
+        }
+
+        // Children in the same slot
+        if from.value == to.value {
+          loop;
+        }
+
+        let parent = match to.parent {
+          Some(p) => p,
+          None => to.id
+        };
+        let same_value = match from.parent {
+          Some(p) => p == parent,
+          None => from.id == parent
+        };
+
+        // Value was stored into the slot on every path leading to this gap
+        let single = !multiple.contains(&parent.to_uint());
+        let redundant = single && same_value && do stores.any() |store| {
+          let (store_pos, store_parent, ref value) = *store;
+          let store_block = self.get_instr(&store_pos).block;
+          store_parent == parent && *value == to.value &&
+              if store_block == block {
+                store_pos < pos
+              } else {
+                tree.dominates(&store_block, &block)
+              }
+        };
+        if !redundant {
+          result.push(action.clone());
+        }
+      }
+      self.gaps.find_mut(id).unwrap().actions = result;
     }
   }
 
@@ -655,6 +1312,7 @@ impl<G: GroupHelper<R>,
       let live_out = self.get_block(block_id).live_out.clone();
       let block_from = self.get_block(block_id).start();
       let block_to = self.get_block(block_id).end();
+      let mut to_phi_inputs = ~[];
 
       // Assume that each live_out interval lives for the whole time of block
       // NOTE: we'll shorten it later if definition of this interval appears to
@@ -667,62 +1325,168 @@ impl<G: GroupHelper<R>,
       for &instr_id in instructions.rev_iter() {
         let instr = self.get_instr(&instr_id).clone();
 
-        // Call instructions should swap out all used registers into stack slots
+        // Call instructions should swap out clobbered registers into stack
+        // slots
         let groups: ~[G] = GroupHelper::groups();
         for group in groups.iter() {
           self.physical.insert(group.to_uint(), ~SmallIntMap::new());
-          if instr.kind.clobbers(group) {
-            let regs = group.registers();
-            for reg in regs.iter() {
-              self.get_mut_interval(physical.get(&group.to_uint())
-                  .get(&reg.to_uint()))
-                  .add_range(instr_id, instr_id.next());
-            }
+          for reg in instr.kind.clobbers(group).iter() {
+            self.get_mut_interval(physical.get(&group.to_uint())
+                .get(&reg.to_uint()))
+                .add_range(instr_id, instr_id.next());
           }
         }
 
-        // Process output
-        match instr.output {
-          Some(output) => {
-            // Call instructions are defining their value after the call
-            let group = self.get_interval(&output).value.group();
-            let pos = if instr.kind.clobbers(&group) {
-              instr_id.next()
-            } else {
-              instr_id
-            };
+        // Projections are defined by their instruction
+        match instr.kind {
+          Proj(_) => loop,
+          _ => ()
+        };
+
+        // Process outputs, parameters are arriving in their locations
+        let outputs = self.get_outputs(&instr_id);
+        let out_kinds = match instr.kind {
+          Param(_) => ~[match self.params.get(&outputs[0].to_uint()) {
+            &RegisterVal(ref r) => UseFixed(r.clone()),
+            &StackVal(ref g, slot) => UseFixedStack(g.clone(), slot),
+            &VirtualVal(_) => fail!("Parameter should have a location")
+          }],
+          _ => instr.kind.result_kinds()
+        };
+        for (i, &output) in outputs.iter().enumerate() {
+          // Call instructions are defining their value after the call,
+          // SSA phis and parameters are defining their value at the block
+          // start, two-address instructions are receiving a copy of the input
+          // in the gap before them
+          let group = self.get_interval(&output).value.group();
+          let same_as = out_kinds[i].same_as();
+          match same_as {
+            Some(input) if input >= instr.inputs.len() => {
+              return Err(~"Two-address output refers to missing input");
+            },
+            _ => ()
+          }
+          let pos = match instr.kind {
+            Phi(_) | Param(_) => block_from,
+            ToPhi(_) => self.to_phi_run_start(&instr_id),
+            _ if same_as.is_some() => instr_id.prev(),
+            _ if self.clobbers(&group, &instr_id) => instr_id.next(),
+            _ => instr_id
+          };
+
+          if self.get_interval(&output).ranges.len() != 0  {
+            // Shorten range if output outlives block, or is used anywhere
+            let range = self.get_mut_interval(&output).first_range();
+            range.start = pos;
 
-            if self.get_interval(&output).ranges.len() != 0  {
-              // Shorten range if output outlives block, or is used anywhere
-              self.get_mut_interval(&output).first_range().start = pos;
-            } else {
-              // Add short range otherwise
-              self.get_mut_interval(&output).add_range(pos, pos.next());
+            // Call's output might be used by two-address instruction right at
+            // the gap where it is defined
+            if range.end <= pos {
+              range.end = pos.next();
             }
-            let out_kind = instr.kind.result_kind().unwrap();
-            self.get_mut_interval(&output).add_use(out_kind, pos);
-          },
-          None => ()
+          } else if same_as.is_some() {
+            // Output is written by the instruction itself
+            self.get_mut_interval(&output).add_range(pos, instr_id.next());
+          } else {
+            // Add short range otherwise
+            self.get_mut_interval(&output).add_range(pos, pos.next());
+          }
+
+          match same_as {
+            Some(input) => {
+              let input = self.get_output(&instr.inputs[input]);
+              self.get_mut_interval(&output).add_use(UseRegister(group),
+                                                     instr_id);
+              if self.get_interval(&output).hint.is_none() {
+                self.get_mut_interval(&output).hint = Some(input);
+              }
+            },
+            None => {
+              self.get_mut_interval(&output).add_use(out_kinds[i].clone(),
+                                                     pos);
+            }
+          }
         }
 
-        // Process temporary
-        for tmp in instr.temporary.iter() {
+        // Process temporary, call instructions are using them only before
+        // registers are clobbered, i.e. in the gap before the call
+        let tmp_kinds = instr.kind.temporary();
+        for (i, tmp) in instr.temporary.iter().enumerate() {
           let group = self.get_interval(tmp).value.group();
-          if instr.kind.clobbers(&group) {
-            return Err(~"Call instruction can't have temporary registers");
-          }
-          self.get_mut_interval(tmp).add_range(instr_id, instr_id.next());
-          self.get_mut_interval(tmp).add_use(group.use_reg(), instr_id);
+          let pos = if self.clobbers(&group, &instr_id) {
+            instr_id.prev()
+          } else {
+            instr_id
+          };
+          self.get_mut_interval(tmp).add_range(pos, pos.next());
+          self.get_mut_interval(tmp).add_use(tmp_kinds[i].clone(), pos);
+        }
+
+        // SSA phi's inputs are used at the end of predecessors
+        match instr.kind {
+          Phi(_) => loop,
+          _ => ()
+        };
+
+        // Inputs of ToPhis are used at the start of the run, process them
+        // when all outputs of the run are known
+        match instr.kind {
+          ToPhi(_) => {
+            to_phi_inputs.push((instr.inputs[0], instr.kind.use_kind(0)));
+
+            let start = self.to_phi_run_start(&instr_id);
+            if start != instr_id {
+              loop;
+            }
+            for &(input_instr, ref kind) in to_phi_inputs.iter() {
+              let input = self.get_output(&input_instr);
+              if !self.get_interval(&input).covers(start.prev()) {
+                self.get_mut_interval(&input).add_range(block_from, start);
+              }
+              self.get_mut_interval(&input).add_use(kind.clone(), start);
+            }
+            to_phi_inputs = ~[];
+            loop;
+          },
+          _ => ()
+        };
+
+        // Early clobbered outputs are written before any input is read
+        let mut early_clobber = false;
+        for (i, _) in out_kinds.iter().enumerate() {
+          early_clobber = early_clobber || instr.kind.early_clobber(i);
         }
 
         // Process inputs
         for (i, input_instr) in instr.inputs.iter().enumerate() {
           let input = self.get_output(input_instr);
-          if !self.get_interval(&input).covers(instr_id) {
-            self.get_mut_interval(&input).add_range(block_from, instr_id);
+
+          // Inputs of two-address instructions are read in the gap before
+          // them, and copied into the output
+          let same_as = out_kinds.any(|k| k.same_as() == Some(i));
+          let (pos, kind) = if same_as {
+            (instr_id.prev(), UseAny(instr.kind.use_kind(i).group()))
+          } else {
+            // Rematerializable values are never read from the stack
+            match instr.kind.use_kind(i) {
+              UseAny(g) if self.is_rematerializable(input_instr) =>
+                  (instr_id, UseRegister(g)),
+              kind => (instr_id, kind)
+            }
+          };
+
+          // Inputs read after outputs are written should not share location
+          // with them
+          let end = if !same_as && (early_clobber ||
+                                    instr.kind.use_timing(i) == UseAtEnd) {
+            pos.next()
+          } else {
+            pos
+          };
+          if !self.get_interval(&input).covers(pos) {
+            self.get_mut_interval(&input).add_range(block_from, end);
           }
-          let kind = instr.kind.use_kind(i);
-          self.get_mut_interval(&input).add_use(kind, instr_id);
+          self.get_mut_interval(&input).add_use(kind, pos);
         }
       }
     }
@@ -759,6 +1523,82 @@ impl<G: GroupHelper<R>,
         i += 1;
       }
     }
+
+    // Intervals should be in stack at stack uses and in register at register
+    // uses, split them between uses that can't share the location
+    let mut list = ~[];
+    for (_, interval) in self.intervals.iter() {
+      if interval.uses.any(|u| { u.kind.is_stack() }) {
+        list.push(interval.id);
+      }
+    }
+    for id in list.iter() {
+      let mut uses = self.get_interval(id).uses.clone();
+      do uses.retain |u| {
+        !u.kind.is_any()
+      };
+
+      let mut i = 0;
+      while i < uses.len() - 1 {
+        let (a, b) = (&uses[i], &uses[i + 1]);
+        let conflict = match (&a.kind, &b.kind) {
+          (&UseFixedStack(_, s0), &UseFixedStack(_, s1)) => s0 != s1,
+          _ => a.kind.is_stack() != b.kind.is_stack()
+        };
+        if conflict && a.pos < b.pos {
+          let split_pos = self.optimal_split_pos(&a.kind.group(),
+                                                 a.pos,
+                                                 b.pos);
+          self.split_at(id, split_pos);
+        }
+
+        i += 1;
+      }
+    }
+
+    // Fixed stack slots might be used by other values too (e.g. outgoing
+    // arguments), occupy them only at the use itself
+    let mut list = ~[];
+    for (_, interval) in self.intervals.iter() {
+      let parent = match interval.parent {
+        Some(parent) => parent,
+        None => interval.id
+      };
+      for u in interval.uses.iter() {
+        match u.kind {
+          UseFixedStack(_, _) => list.push((parent, u.pos)),
+          _ => ()
+        }
+      }
+    }
+    for &(parent, pos) in list.iter() {
+      // Split in the gap before the use
+      let mut child = self.child_with_use_at(&parent, pos).unwrap();
+      let before = if self.is_gap(&pos) { pos } else { pos.prev() };
+      if self.get_interval(&child).start() < before {
+        child = self.split_at(&child, before);
+      }
+
+      // And in the first gap after it
+      let end = self.get_interval(&child).end();
+      let mut after = pos.next();
+      if after < end && !self.is_gap(&after) {
+        after = after.next();
+      }
+      if after >= end {
+        loop;
+      }
+      if !self.get_interval(&child).covers(after) {
+        // Value isn't live right after the use, split where it is live again
+        for range in self.get_interval(&child).ranges.iter() {
+          if range.start > after {
+            after = range.start;
+            break;
+          }
+        }
+      }
+      self.split_at(&child, after);
+    }
   }
 
   #[cfg(test)]
@@ -782,11 +1622,41 @@ impl<G: GroupHelper<R>,
             UseFixed(ref r0) => match interval.value {
               RegisterVal(ref r1) if r0 == r1 => (), // ok
               _ => fail!("Expected fixed register")
-            }
+            },
+            UseStack(_) => match interval.value {
+              StackVal(_, _) => (), // ok
+              _ => fail!("Stack slot expected")
+            },
+            UseFixedStack(_, ref s0) => match interval.value {
+              StackVal(_, ref s1) if s0 == s1 => (), // ok
+              _ => fail!("Expected fixed stack slot")
+            },
+            UseSameAs(_, _) => fail!("UseSameAs is a result kind only")
           }
         }
       }
     }
+
+    // Aliasing registers shouldn't be occupied at the same time, no matter
+    // which group was allocated first
+    for (&id, interval) in self.intervals.iter() {
+      let aliases = match interval.value {
+        RegisterVal(ref r) if interval.ranges.len() > 0 => r.aliases(),
+        _ => loop
+      };
+      for (&other_id, other) in self.intervals.iter() {
+        if interval.fixed && other.fixed {
+          loop;
+        }
+        match other.value {
+          RegisterVal(ref r) if aliases.contains(r) => {
+            assert!(self.get_intersection(&IntervalId(id),
+                                          &IntervalId(other_id)).is_none());
+          },
+          _ => ()
+        }
+      }
+    }
   }
   #[cfg(not(test))]
   fn verify(&self) {
@@ -799,15 +1669,20 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > AllocatorState<G, R> {
     return if self.spills.len() > 0 {
       self.spills.shift()
     } else {
-      let slot = self.spill_count;
-      self.spill_count += 1;
+      // Skip slots reserved by fixed stack uses
+      let mut slot = self.spill_count;
+      while self.fixed_spills.contains(&slot) {
+        slot += 1;
+      }
+      self.spill_count = slot + 1;
       StackVal(*self.group.clone(), StackId(slot))
     }
   }
 
   fn to_handled(&mut self, value: &Value<G, R>) {
     match value {
-      &StackVal(ref group, slot) => {
+      &StackVal(ref group, slot)
+          if !self.fixed_spills.contains(&slot.to_uint()) => {
         self.spills.push(StackVal(group.clone(), slot))
       },
       _ => ()
@@ -1,12 +1,23 @@
 // Private imports
-use linearscan::graph::{Block, Instruction, User, Phi, ToPhi,
-                        UseAny, UseRegister, UseFixed};
+use linearscan::graph::{Block, Instruction, User, Phi, ToPhi, Proj, Param,
+                        UseAny, UseRegister, UseFixed, UseStack, UseFixedStack,
+                        UseSameAs, expect_ok};
 
 // Public API
-pub use linearscan::graph::{Graph, UseKind,
+pub use linearscan::graph::{Graph, UseKind, UseTiming, UseAtStart, UseAtEnd,
                             BlockId, InstrId, StackId,
-                            Value, RegisterVal, StackVal};
-pub use linearscan::allocator::{Allocator};
+                            Value, RegisterVal, StackVal,
+                            GraphError, AlreadyAdded, AlreadyEnded, EmptyBlock,
+                            NotPhi, NoOutput, NotInBlock,
+                            UnknownBlock, UnknownInstr, NoRoot, NotEnded,
+                            NotDominated, WrongInputCount, GroupMismatch,
+                            StillUsed, NotAdded, AlreadyPrepared,
+                            VirtualParam, WrongSameAs, NotMovable,
+                            LocationTaken, IsProjection, WrongUseKind,
+                            WrongTemporary};
+pub use linearscan::allocator::{Allocator, AllocatorResult, AllocatorConfig};
+pub use linearscan::dominance::{Dominance, DominatorTree, Loop};
+pub use linearscan::validate::{Validate};
 pub use linearscan::generator::{Generator, GeneratorFunctions};
 
 struct BlockBuilder<'self, K, G, R> {
@@ -25,11 +36,20 @@ pub trait RegisterHelper<Group>: Clone+Eq {
   fn group(&self) -> Group;
   fn to_uint(&self) -> uint;
   fn from_uint(g: &Group, i: uint) -> Self;
+
+  // Registers of other groups sharing storage with this one
+  fn aliases(&self) -> ~[Self] { ~[] }
+
+  // True if register should be preserved by the function using it
+  fn callee_saved(&self) -> bool { false }
 }
 
 pub trait GroupAutoHelper<Register> {
   fn use_any(&self) -> UseKind<Self, Register>;
   fn use_reg(&self) -> UseKind<Self, Register>;
+  fn use_stack(&self) -> UseKind<Self, Register>;
+  fn use_fixed_stack(&self, slot: StackId) -> UseKind<Self, Register>;
+  fn use_same_as(&self, i: uint) -> UseKind<Self, Register>;
 }
 
 pub trait RegisterAutoHelper<Group> {
@@ -37,10 +57,18 @@ pub trait RegisterAutoHelper<Group> {
 }
 
 pub trait KindHelper<G: GroupHelper<R>, R: RegisterHelper<G> >: Clone {
-  fn clobbers(&self, group: &G) -> bool;
-  fn temporary(&self) -> ~[G];
+  fn clobbers(&self, group: &G) -> ~[R];
+  fn temporary(&self) -> ~[UseKind<G, R>];
+  // Expected number of inputs, `None` skips the check in `validate()`
+  fn input_count(&self) -> Option<uint> { None }
   fn use_kind(&self, i: uint) -> UseKind<G, R>;
-  fn result_kind(&self) -> Option<UseKind<G, R> >;
+  fn use_timing(&self, _: uint) -> UseTiming { UseAtStart }
+  fn result_kinds(&self) -> ~[UseKind<G, R>];
+  fn early_clobber(&self, _: uint) -> bool { false }
+  // Instruction without inputs that is cheaper to recompute than to load
+  // from the stack. NOTE: `UseAny` inputs reading its output are treated as
+  // `UseRegister`, so spilled values are never read from their slot
+  fn rematerializable(&self) -> bool { false }
 }
 
 pub trait GraphAPI<K: KindHelper<G, R>,
@@ -49,16 +77,67 @@ pub trait GraphAPI<K: KindHelper<G, R>,
   fn empty_block(&mut self) -> BlockId;
   fn block(&mut self, body: &fn(b: &mut BlockBuilder<K, G, R>)) -> BlockId;
   fn phi(&mut self, group: G) -> InstrId;
+  fn param(&mut self, location: Value<G, R>) -> InstrId;
+  fn try_param(&mut self,
+               location: Value<G, R>) -> Result<InstrId, GraphError>;
   fn with_block(&mut self,
                 id: BlockId,
                 body: &fn(b: &mut BlockBuilder<K, G, R>));
   fn new_instr(&mut self, kind: K, args: ~[InstrId]) -> InstrId;
+  fn add_incoming(&mut self, phi: InstrId, pred: BlockId, value: InstrId);
+  fn try_add_incoming(&mut self,
+                      phi: InstrId,
+                      pred: BlockId,
+                      value: InstrId) -> Result<(), GraphError>;
   fn set_root(&mut self, id: BlockId);
+  fn remove_instr(&mut self, id: InstrId);
+  fn try_remove_instr(&mut self, id: InstrId) -> Result<(), GraphError>;
+  fn replace_all_uses(&mut self, old: InstrId, new: InstrId);
+  fn try_replace_all_uses(&mut self,
+                          old: InstrId,
+                          new: InstrId) -> Result<(), GraphError>;
+  fn insert_before(&mut self, anchor: InstrId, id: InstrId);
+  fn try_insert_before(&mut self,
+                       anchor: InstrId,
+                       id: InstrId) -> Result<(), GraphError>;
+  fn insert_after(&mut self, anchor: InstrId, id: InstrId);
+  fn try_insert_after(&mut self,
+                      anchor: InstrId,
+                      id: InstrId) -> Result<(), GraphError>;
+  fn move_instr_to_block(&mut self, id: InstrId, block: BlockId);
+  fn try_move_instr_to_block(&mut self,
+                             id: InstrId,
+                             block: BlockId) -> Result<(), GraphError>;
+}
+
+trait GraphAPIHelper {
+  // Return error if instruction isn't in any block or graph is already
+  // prepared for allocation
+  fn check_added(&self, id: InstrId) -> Result<(), GraphError>;
+
+  // Add instruction to the block of `anchor` at `offset` from it
+  fn insert_at(&mut self,
+               anchor: InstrId,
+               id: InstrId,
+               offset: uint) -> Result<(), GraphError>;
+
+  // Return projections of instruction's extra outputs
+  fn projections(&self, id: InstrId) -> ~[InstrId];
+
+  // Move parameters to the start of the root block, in order of creation
+  fn place_params(&mut self, root: BlockId);
 }
 
 impl<G: GroupHelper<R>, R: RegisterHelper<G> > GroupAutoHelper<R> for G {
   fn use_any(&self) -> UseKind<G, R> { UseAny(self.clone()) }
   fn use_reg(&self) -> UseKind<G, R> { UseRegister(self.clone()) }
+  fn use_stack(&self) -> UseKind<G, R> { UseStack(self.clone()) }
+  fn use_fixed_stack(&self, slot: StackId) -> UseKind<G, R> {
+    UseFixedStack(self.clone(), slot)
+  }
+  fn use_same_as(&self, i: uint) -> UseKind<G, R> {
+    UseSameAs(self.clone(), i)
+  }
 }
 
 impl<G: GroupHelper<R>, R: RegisterHelper<G> > RegisterAutoHelper<G> for R {
@@ -97,6 +176,44 @@ impl<G: GroupHelper<R>,
     return res;
   }
 
+  /// Create function parameter arriving in the specified register or stack
+  /// slot, it is defined at the start of the root block
+  pub fn param(&mut self, location: Value<G, R>) -> InstrId {
+    expect_ok(self.try_param(location))
+  }
+
+  pub fn try_param(&mut self,
+                   location: Value<G, R>) -> Result<InstrId, GraphError> {
+    if self.prepared {
+      return Err(AlreadyPrepared);
+    }
+    if location.is_virtual() {
+      return Err(VirtualParam);
+    }
+    for (_, instr) in self.instructions.iter() {
+      match instr.kind {
+        Param(_) => {
+          let output = instr.output.unwrap();
+          if self.params.find(&output.to_uint()) == Some(&location) {
+            return Err(LocationTaken(instr.id));
+          }
+        },
+        _ => ()
+      }
+    }
+
+    let res = Instruction::new(self, Param(location.group()), ~[]);
+    let output = self.get_output(&res);
+    self.params.insert(output.to_uint(), location);
+
+    // Parameters created before the root are placed by `set_root`
+    match self.root {
+      Some(root) => self.place_params(root),
+      None => ()
+    }
+    return Ok(res);
+  }
+
   /// Perform operations on block
   pub fn with_block(&mut self,
                     id: BlockId,
@@ -113,9 +230,353 @@ impl<G: GroupHelper<R>,
     return Instruction::new(self, User(kind), args);
   }
 
+  /// Add `value` to SSA phi's inputs, it'll be moved into phi on the edge
+  /// from `pred` to phi's block
+  pub fn add_incoming(&mut self, phi: InstrId, pred: BlockId, value: InstrId) {
+    expect_ok(self.try_add_incoming(phi, pred, value))
+  }
+
+  pub fn try_add_incoming(&mut self,
+                          phi: InstrId,
+                          pred: BlockId,
+                          value: InstrId) -> Result<(), GraphError> {
+    if !self.instructions.contains_key(&phi.to_uint()) {
+      return Err(UnknownInstr(phi));
+    }
+    match self.get_instr(&phi).kind {
+      Phi(_) => (),
+      _ => { return Err(NotPhi(phi)); }
+    };
+    if !self.blocks.contains_key(&pred.to_uint()) {
+      return Err(UnknownBlock(pred));
+    }
+    let out = match self.try_get_output(&phi) {
+      Ok(out) => out,
+      Err(err) => { return Err(err); }
+    };
+    let inp = match self.try_get_output(&value) {
+      Ok(inp) => inp,
+      Err(err) => { return Err(err); }
+    };
+
+    // Insert one hint
+    if self.get_interval(&out).hint.is_none() {
+      self.get_mut_interval(&out).hint = Some(inp);
+    }
+
+    self.get_mut_instr(&phi).inputs.push(value);
+    self.get_mut_instr(&phi).incoming.push(pred);
+    return Ok(());
+  }
+
   /// Set graph's root block
   pub fn set_root(&mut self, id: BlockId) {
     self.root = Some(id);
+    self.place_params(id);
+  }
+
+  /// Remove instruction from the graph, it should not be used by any other
+  /// instruction
+  pub fn remove_instr(&mut self, id: InstrId) {
+    expect_ok(self.try_remove_instr(id))
+  }
+
+  pub fn try_remove_instr(&mut self, id: InstrId) -> Result<(), GraphError> {
+    if self.prepared {
+      return Err(AlreadyPrepared);
+    }
+    if !self.instructions.contains_key(&id.to_uint()) {
+      return Err(UnknownInstr(id));
+    }
+    match self.get_instr(&id).kind {
+      Proj(_) => { return Err(IsProjection(id)); },
+      _ => ()
+    }
+
+    let is_phi = self.phis.contains(&id);
+    let output = self.get_instr(&id).output;
+    let projs = self.projections(id);
+    for (_, instr) in self.instructions.iter() {
+      // Inputs of phis are ToPhis, they are updated below
+      if instr.id == id || self.phis.contains(&instr.id) ||
+         projs.contains(&instr.id) {
+        loop;
+      }
+
+      // Phi is also used by ToPhis writing into it
+      let writes_phi = match instr.kind {
+        ToPhi(_) => is_phi && instr.output == output,
+        _ => false
+      };
+      let uses_proj = do instr.inputs.any() |input| {
+        projs.contains(input)
+      };
+      if writes_phi || uses_proj || instr.inputs.contains(&id) {
+        return Err(StillUsed(id, instr.id));
+      }
+    }
+
+    // Projections are removed together with their instruction
+    for proj in projs.iter() {
+      let instr = self.instructions.pop(&proj.to_uint()).unwrap();
+      let block = self.get_mut_block(&instr.block);
+      let pos = block.instructions.position_elem(proj).unwrap();
+      block.instructions.remove(pos);
+    }
+
+    let instr = self.instructions.pop(&id.to_uint()).unwrap();
+    match instr.kind {
+      Param(_) => { self.params.pop(&instr.output.unwrap().to_uint()); },
+      _ => ()
+    }
+    if is_phi {
+      let pos = self.phis.position_elem(&id).unwrap();
+      self.phis.remove(pos);
+    } else if instr.added {
+      let block = self.get_mut_block(&instr.block);
+      let pos = block.instructions.position_elem(&id).unwrap();
+      block.instructions.remove(pos);
+    }
+
+    // Remove ToPhi from phis' inputs
+    let phis = self.phis.clone();
+    for phi in phis.iter() {
+      do self.get_mut_instr(phi).inputs.retain |input| { *input != id };
+    }
+
+    // Remove intervals (ToPhi's output belongs to phi, and projection's to
+    // its instruction)
+    let mut intervals = instr.temporary.clone();
+    match instr.kind {
+      ToPhi(_) | Proj(_) => (),
+      _ => {
+        match instr.output {
+          Some(output) => intervals.push(output),
+          None => ()
+        }
+        intervals.push_all(instr.extra_outputs);
+      }
+    }
+    for interval in intervals.iter() {
+      self.intervals.pop(&interval.to_uint());
+    }
+    for (_, interval) in self.intervals.mut_iter() {
+      match interval.hint {
+        Some(hint) if intervals.contains(&hint) => interval.hint = None,
+        _ => ()
+      }
+    }
+    return Ok(());
+  }
+
+  /// Use `new` instead of `old` in every instruction's inputs
+  pub fn replace_all_uses(&mut self, old: InstrId, new: InstrId) {
+    expect_ok(self.try_replace_all_uses(old, new))
+  }
+
+  pub fn try_replace_all_uses(&mut self,
+                              old: InstrId,
+                              new: InstrId) -> Result<(), GraphError> {
+    if self.prepared {
+      return Err(AlreadyPrepared);
+    }
+    if !self.instructions.contains_key(&old.to_uint()) {
+      return Err(UnknownInstr(old));
+    }
+    match self.try_get_output(&new) {
+      Ok(_) => (),
+      Err(err) => { return Err(err); }
+    }
+
+    for (_, instr) in self.instructions.mut_iter() {
+      if instr.id == new { loop; }
+
+      // Projection's input is always the instruction defining it
+      match instr.kind {
+        Proj(_) => loop,
+        _ => ()
+      }
+      for input in instr.inputs.mut_iter() {
+        if *input == old {
+          *input = new;
+        }
+      }
+    }
+    return Ok(());
+  }
+
+  /// Add instruction created by `new_instr` right before `anchor`
+  pub fn insert_before(&mut self, anchor: InstrId, id: InstrId) {
+    expect_ok(self.try_insert_before(anchor, id))
+  }
+
+  pub fn try_insert_before(&mut self,
+                           anchor: InstrId,
+                           id: InstrId) -> Result<(), GraphError> {
+    self.insert_at(anchor, id, 0)
+  }
+
+  /// Add instruction created by `new_instr` right after `anchor`
+  pub fn insert_after(&mut self, anchor: InstrId, id: InstrId) {
+    expect_ok(self.try_insert_after(anchor, id))
+  }
+
+  pub fn try_insert_after(&mut self,
+                          anchor: InstrId,
+                          id: InstrId) -> Result<(), GraphError> {
+    self.insert_at(anchor, id, 1)
+  }
+
+  /// Move instruction to the end of other block, or right before its last
+  /// instruction if the block is ended by a branch or a return
+  pub fn move_instr_to_block(&mut self, id: InstrId, block: BlockId) {
+    expect_ok(self.try_move_instr_to_block(id, block))
+  }
+
+  pub fn try_move_instr_to_block(&mut self,
+                                 id: InstrId,
+                                 block: BlockId) -> Result<(), GraphError> {
+    match self.check_added(id) {
+      Ok(_) => (),
+      Err(err) => { return Err(err); }
+    }
+    if !self.blocks.contains_key(&block.to_uint()) {
+      return Err(UnknownBlock(block));
+    }
+
+    // These are bound to the start or the end of their blocks
+    match self.get_instr(&id).kind {
+      User(_) => (),
+      _ => { return Err(NotMovable(id)); }
+    }
+
+    // Source block should not become empty
+    let from = self.get_instr(&id).block;
+    let mut moved = ~[id];
+    moved.push_all(self.projections(id));
+    if from != block &&
+       self.get_block(&from).instructions.len() == moved.len() {
+      return Err(EmptyBlock(from));
+    }
+
+    // Detach instruction and its projections from the block
+    {
+      let from = self.get_mut_block(&from);
+      for instr_id in moved.iter() {
+        let pos = from.instructions.position_elem(instr_id).unwrap();
+        from.instructions.remove(pos);
+      }
+    }
+
+    for instr_id in moved.iter() {
+      self.get_mut_instr(instr_id).block = block;
+    }
+    let target = self.get_mut_block(&block);
+    let mut pos = if target.ended && target.successors.len() != 1 &&
+                     target.instructions.len() > 0 {
+      target.instructions.len() - 1
+    } else {
+      target.instructions.len()
+    };
+    for instr_id in moved.iter() {
+      target.instructions.insert(pos, *instr_id);
+      pos += 1;
+    }
+    return Ok(());
+  }
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > GraphAPIHelper for Graph<K, G, R> {
+  fn check_added(&self, id: InstrId) -> Result<(), GraphError> {
+    if self.prepared {
+      return Err(AlreadyPrepared);
+    }
+    match self.instructions.find(&id.to_uint()) {
+      Some(instr) if instr.added && !self.phis.contains(&id) => Ok(()),
+      Some(_) => Err(NotAdded(id)),
+      None => Err(UnknownInstr(id))
+    }
+  }
+
+  fn insert_at(&mut self,
+               anchor: InstrId,
+               id: InstrId,
+               offset: uint) -> Result<(), GraphError> {
+    match self.check_added(anchor) {
+      Ok(_) => (),
+      Err(err) => { return Err(err); }
+    }
+    match self.get_instr(&anchor).kind {
+      Proj(_) => { return Err(IsProjection(anchor)); },
+      _ => ()
+    }
+    match self.instructions.find(&id.to_uint()) {
+      Some(instr) if instr.added => { return Err(AlreadyAdded(id)); },
+      Some(_) => (),
+      None => { return Err(UnknownInstr(id)); }
+    }
+
+    // Projections should stay right after their instruction
+    let skip = if offset > 0 { self.projections(anchor).len() } else { 0 };
+
+    let block_id = self.get_instr(&anchor).block;
+    {
+      let block = self.get_mut_block(&block_id);
+      let pos = block.instructions.position_elem(&anchor).unwrap() + offset +
+                skip;
+
+      // Nothing could follow the last instruction of ended block
+      if block.ended && pos == block.instructions.len() {
+        return Err(AlreadyEnded(block_id));
+      }
+      block.instructions.insert(pos, id);
+    }
+
+    let instr = self.get_mut_instr(&id);
+    instr.added = true;
+    instr.block = block_id;
+    return Ok(());
+  }
+
+  fn projections(&self, id: InstrId) -> ~[InstrId] {
+    let mut res = ~[];
+    for (_, instr) in self.instructions.iter() {
+      match instr.kind {
+        Proj(_) if instr.inputs[0] == id => res.push(instr.id),
+        _ => ()
+      }
+    }
+    return res;
+  }
+
+  fn place_params(&mut self, root: BlockId) {
+    let mut params = ~[];
+    for (_, instr) in self.instructions.iter() {
+      match instr.kind {
+        Param(_) => params.push(instr.id),
+        _ => ()
+      }
+    }
+
+    for (i, id) in params.iter().enumerate() {
+      // Root might have been changed, remove parameter from the old one
+      let (added, block) = {
+        let instr = self.get_instr(id);
+        (instr.added, instr.block)
+      };
+      if added {
+        let block = self.get_mut_block(&block);
+        let pos = block.instructions.position_elem(id).unwrap();
+        block.instructions.remove(pos);
+      }
+      self.get_mut_block(&root).instructions.insert(i, *id);
+
+      let instr = self.get_mut_instr(id);
+      instr.added = true;
+      instr.block = root;
+    }
   }
 }
 
@@ -123,41 +584,139 @@ impl<'self,
      G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R> > BlockBuilder<'self, K, G, R> {
-  /// add instruction to block
-  pub fn add(&mut self, kind: K, args: ~[InstrId]) -> InstrId {
-    let instr_id = self.graph.new_instr(kind, args);
+  /// add instruction to block, return handles for every output (the first
+  /// one is the instruction itself)
+  pub fn add(&mut self, kind: K, args: ~[InstrId]) -> ~[InstrId] {
+    expect_ok(self.try_add(kind, args))
+  }
+
+  pub fn try_add(&mut self,
+                 kind: K,
+                 args: ~[InstrId]) -> Result<~[InstrId], GraphError> {
+    // Check everything before creating instruction, so that failure won't
+    // leave it in the graph
+    if self.graph.get_block(&self.block).ended {
+      return Err(AlreadyEnded(self.block));
+    }
+    for arg in args.iter() {
+      match self.graph.try_get_output(arg) {
+        Ok(_) => (),
+        Err(err) => { return Err(err); }
+      }
+    }
 
-    self.add_existing(instr_id);
+    let instr_id = self.graph.new_instr(kind, args);
+    match self.try_add_existing(instr_id) {
+      Ok(_) => (),
+      Err(err) => { return Err(err); }
+    }
 
-    return instr_id;
+    // Every extra output is accessible through the projection
+    let mut res = ~[instr_id];
+    let extra = self.graph.get_instr(&instr_id).extra_outputs.clone();
+    for output in extra.iter() {
+      let group = self.graph.get_interval(output).value.group();
+      let proj = Instruction::new_empty(self.graph, Proj(group), ~[instr_id]);
+      self.graph.get_mut_instr(&proj).output = Some(*output);
+      match self.try_add_existing(proj) {
+        Ok(_) => (),
+        Err(err) => { return Err(err); }
+      }
+      res.push(proj);
+    }
+    return Ok(res);
   }
 
   /// add existing instruction to block
   pub fn add_existing(&mut self, instr_id: InstrId) {
-    assert!(!self.graph.get_instr(&instr_id).added);
+    expect_ok(self.try_add_existing(instr_id))
+  }
+
+  pub fn try_add_existing(&mut self,
+                          instr_id: InstrId) -> Result<(), GraphError> {
+    if !self.graph.instructions.contains_key(&instr_id.to_uint()) {
+      return Err(UnknownInstr(instr_id));
+    }
+    if self.graph.get_instr(&instr_id).added {
+      return Err(AlreadyAdded(instr_id));
+    }
+    if self.graph.get_block(&self.block).ended {
+      return Err(AlreadyEnded(self.block));
+    }
+
     self.graph.get_mut_instr(&instr_id).added = true;
     self.graph.get_mut_instr(&instr_id).block = self.block;
+    self.graph.get_mut_block(&self.block).instructions.push(instr_id);
+    return Ok(());
+  }
+
+  /// add SSA phi to the start of block, use `GraphAPI::add_incoming` to
+  /// add its inputs
+  pub fn phi(&mut self, group: G) -> InstrId {
+    let res = Instruction::new(self.graph, Phi(group), ~[]);
+    self.graph.get_mut_instr(&res).added = true;
+    self.graph.get_mut_instr(&res).block = self.block;
 
-    let block = self.graph.get_mut_block(&self.block);
-    assert!(!block.ended);
-    block.instructions.push(instr_id);
+    // Insert after other phis
+    let mut index = 0;
+    for id in self.graph.get_block(&self.block).instructions.iter() {
+      match self.graph.get_instr(id).kind {
+        Phi(_) => { index += 1; },
+        _ => break
+      }
+    }
+    self.graph.get_mut_block(&self.block).instructions.insert(index, res);
+    return res;
   }
 
   /// add arg to existing instruction in block
   pub fn add_arg(&mut self, id: InstrId, arg: InstrId) {
-    assert!(self.graph.get_instr(&id).block == self.block);
+    expect_ok(self.try_add_arg(id, arg))
+  }
+
+  pub fn try_add_arg(&mut self,
+                     id: InstrId,
+                     arg: InstrId) -> Result<(), GraphError> {
+    if !self.graph.instructions.contains_key(&id.to_uint()) {
+      return Err(UnknownInstr(id));
+    }
+    if self.graph.get_instr(&id).block != self.block {
+      return Err(NotInBlock(id, self.block));
+    }
+    match self.graph.try_get_output(&arg) {
+      Ok(_) => (),
+      Err(err) => { return Err(err); }
+    }
     self.graph.get_mut_instr(&id).inputs.push(arg);
+    return Ok(());
   }
 
   /// add phi movement to block
   pub fn to_phi(&mut self, input: InstrId, phi: InstrId) {
+    expect_ok(self.try_to_phi(input, phi))
+  }
+
+  pub fn try_to_phi(&mut self,
+                    input: InstrId,
+                    phi: InstrId) -> Result<(), GraphError> {
+    if !self.graph.instructions.contains_key(&phi.to_uint()) {
+      return Err(UnknownInstr(phi));
+    }
     let group = match self.graph.get_instr(&phi).kind {
       Phi(ref group) => group.clone(),
-      _ => fail!("Expected Phi argument")
+      _ => { return Err(NotPhi(phi)); }
+    };
+    let out = match self.graph.try_get_output(&phi) {
+      Ok(out) => out,
+      Err(err) => { return Err(err); }
+    };
+    let inp = match self.graph.try_get_output(&input) {
+      Ok(inp) => inp,
+      Err(err) => { return Err(err); }
     };
-    let out = self.graph.get_instr(&phi).output.expect("Phi output");
-    let inp = self.graph.get_instr(&input).output
-                  .expect("Phi input output");
+    if self.graph.get_block(&self.block).ended {
+      return Err(AlreadyEnded(self.block));
+    }
 
     // Insert one hint
     if self.graph.get_interval(&out).hint.is_none() {
@@ -166,37 +725,87 @@ impl<'self,
 
     let res = Instruction::new_empty(self.graph, ToPhi(group), ~[input]);
     self.graph.get_mut_instr(&res).output = Some(out);
-    self.add_existing(res);
+    match self.try_add_existing(res) {
+      Ok(_) => (),
+      Err(err) => { return Err(err); }
+    }
     self.graph.get_mut_instr(&phi).inputs.push(res);
-    assert!(self.graph.get_instr(&phi).inputs.len() <= 2);
+    return Ok(());
   }
 
   /// end block
   pub fn end(&mut self) {
-    let block = self.graph.get_mut_block(&self.block);
-    assert!(!block.ended);
-    assert!(block.instructions.len() > 0);
-    block.ended = true;
+    expect_ok(self.try_end())
+  }
+
+  pub fn try_end(&mut self) -> Result<(), GraphError> {
+    match self.check_end() {
+      Ok(_) => (),
+      Err(err) => { return Err(err); }
+    }
+    self.graph.get_mut_block(&self.block).ended = true;
+    return Ok(());
   }
 
   /// add `target_id` to block's successors
   pub fn goto(&mut self, target_id: BlockId) {
-    self.graph.get_mut_block(&self.block).add_successor(target_id);
-    self.graph.get_mut_block(&target_id).add_predecessor(self.block);
-    self.end();
+    expect_ok(self.try_goto(target_id))
+  }
+
+  pub fn try_goto(&mut self, target_id: BlockId) -> Result<(), GraphError> {
+    self.try_switch([target_id])
   }
 
   /// add `left` and `right` to block's successors
   pub fn branch(&mut self, left: BlockId, right: BlockId) {
-    self.graph.get_mut_block(&self.block).add_successor(left)
-                                         .add_successor(right);
-    self.graph.get_mut_block(&left).add_predecessor(self.block);
-    self.graph.get_mut_block(&right).add_predecessor(self.block);
-    self.end();
+    expect_ok(self.try_branch(left, right))
+  }
+
+  pub fn try_branch(&mut self,
+                    left: BlockId,
+                    right: BlockId) -> Result<(), GraphError> {
+    self.try_switch([left, right])
+  }
+
+  /// add every block from `targets` to block's successors (multi-way branch),
+  /// the last instruction of block should pick one of them
+  pub fn switch(&mut self, targets: &[BlockId]) {
+    expect_ok(self.try_switch(targets))
+  }
+
+  pub fn try_switch(&mut self, targets: &[BlockId]) -> Result<(), GraphError> {
+    // Check everything before modifying the graph
+    match self.check_end() {
+      Ok(_) => (),
+      Err(err) => { return Err(err); }
+    }
+    for target_id in targets.iter() {
+      if !self.graph.blocks.contains_key(&target_id.to_uint()) {
+        return Err(UnknownBlock(*target_id));
+      }
+    }
+
+    for target_id in targets.iter() {
+      self.graph.get_mut_block(&self.block).add_successor(*target_id);
+      self.graph.get_mut_block(target_id).add_predecessor(self.block);
+    }
+    self.try_end()
   }
 
   /// mark block as root
   pub fn make_root(&mut self) {
     self.graph.set_root(self.block);
   }
+
+  /// Return error if block can't be ended
+  fn check_end(&self) -> Result<(), GraphError> {
+    let block = self.graph.get_block(&self.block);
+    if block.ended {
+      Err(AlreadyEnded(self.block))
+    } else if block.instructions.len() == 0 {
+      Err(EmptyBlock(self.block))
+    } else {
+      Ok(())
+    }
+  }
 }
@@ -0,0 +1,284 @@
+use extra::smallintmap::SmallIntMap;
+use extra::bitv::BitvSet;
+use linearscan::{KindHelper, RegisterHelper, GroupHelper};
+use linearscan::graph::{Graph, BlockId};
+
+pub struct DominatorTree {
+  // Immediate dominator of every reachable block (root is dominated by itself)
+  idom: ~SmallIntMap<BlockId>,
+  // Reachable blocks in reverse postorder
+  order: ~[BlockId],
+  // Position of every reachable block in `order`
+  index: ~SmallIntMap<uint>
+}
+
+pub struct Loop {
+  // Loop header, dominates every block of the loop
+  header: BlockId,
+  // Sources of back edges to the header
+  ends: ~[BlockId],
+  // Every block of the loop (including header and nested loops' blocks)
+  blocks: ~[BlockId],
+  // Index of innermost enclosing loop
+  parent: Option<uint>,
+  // Nesting depth (outermost loops have depth 1)
+  depth: uint,
+  // False if the loop may be entered not only through its header
+  reducible: bool
+}
+
+pub trait Dominance {
+  // Build dominator tree of blocks reachable from root
+  fn dominators(&self) -> ~DominatorTree;
+
+  // Build loop-nesting forest, outer loops precede nested ones
+  fn loops(&self) -> ~[Loop];
+}
+
+trait DominanceHelper {
+  // Return blocks reachable from root in reverse postorder
+  fn reverse_postorder(&self) -> ~[BlockId];
+
+  // Return edges leading to blocks on the depth-first search stack
+  // (`from`, `to`)
+  fn retreating_edges(&self) -> ~[(BlockId, BlockId)];
+
+  // Return blocks reachable from specified one
+  fn reachable_from(&self, id: &BlockId) -> ~BitvSet;
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > DominanceHelper for Graph<K, G, R> {
+  fn reverse_postorder(&self) -> ~[BlockId] {
+    let root = self.root.expect("Root block");
+    let mut order = ~[];
+    let mut visited = ~BitvSet::new();
+
+    // Stack of blocks and indexes of their next successors to visit
+    let mut stack = ~[(root, 0)];
+    visited.insert(root.to_uint());
+
+    while stack.len() > 0 {
+      let (cur, i) = *stack.last();
+      let successors = self.get_block(&cur).successors.clone();
+      if i < successors.len() {
+        stack[stack.len() - 1] = (cur, i + 1);
+        if visited.insert(successors[i].to_uint()) {
+          stack.push((successors[i], 0));
+        }
+      } else {
+        stack.pop();
+        order.push(cur);
+      }
+    }
+
+    order.reverse();
+    return order;
+  }
+
+  fn retreating_edges(&self) -> ~[(BlockId, BlockId)] {
+    let root = self.root.expect("Root block");
+    let mut result = ~[];
+    let mut visited = ~BitvSet::new();
+    let mut on_stack = ~BitvSet::new();
+
+    let mut stack = ~[(root, 0)];
+    visited.insert(root.to_uint());
+    on_stack.insert(root.to_uint());
+
+    while stack.len() > 0 {
+      let (cur, i) = *stack.last();
+      let successors = self.get_block(&cur).successors.clone();
+      if i < successors.len() {
+        let succ = successors[i];
+        stack[stack.len() - 1] = (cur, i + 1);
+        if visited.insert(succ.to_uint()) {
+          on_stack.insert(succ.to_uint());
+          stack.push((succ, 0));
+        } else if on_stack.contains(&succ.to_uint()) {
+          result.push((cur, succ));
+        }
+      } else {
+        stack.pop();
+        on_stack.remove(&cur.to_uint());
+      }
+    }
+
+    return result;
+  }
+
+  fn reachable_from(&self, id: &BlockId) -> ~BitvSet {
+    let mut result = ~BitvSet::new();
+    let mut queue = ~[*id];
+    while queue.len() > 0 {
+      let cur = queue.pop();
+      if !result.insert(cur.to_uint()) { loop; }
+      for succ in self.get_block(&cur).successors.iter() {
+        queue.push(*succ);
+      }
+    }
+    return result;
+  }
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > Dominance for Graph<K, G, R> {
+  // Cooper, Harvey, Kennedy - "A Simple, Fast Dominance Algorithm"
+  fn dominators(&self) -> ~DominatorTree {
+    let order = self.reverse_postorder();
+    let mut tree = ~DominatorTree {
+      idom: ~SmallIntMap::new(),
+      order: order.clone(),
+      index: ~SmallIntMap::new()
+    };
+    for (i, id) in order.iter().enumerate() {
+      tree.index.insert(id.to_uint(), i);
+    }
+    tree.idom.insert(order[0].to_uint(), order[0]);
+
+    let mut change = true;
+    while change {
+      change = false;
+
+      for id in order.tail().iter() {
+        let mut new_idom = None;
+        for pred in self.get_block(id).predecessors.iter() {
+          // Skip unprocessed and unreachable predecessors
+          if !tree.idom.contains_key(&pred.to_uint()) {
+            loop;
+          }
+          new_idom = match new_idom {
+            None => Some(*pred),
+            Some(other) => Some(tree.intersect(*pred, other))
+          };
+        }
+
+        let new_idom = new_idom.expect("Processed predecessor");
+        let changed = match tree.idom.find(&id.to_uint()) {
+          Some(old) => *old != new_idom,
+          None => true
+        };
+        if changed {
+          tree.idom.insert(id.to_uint(), new_idom);
+          change = true;
+        }
+      }
+    }
+
+    return tree;
+  }
+
+  fn loops(&self) -> ~[Loop] {
+    let tree = self.dominators();
+    let retreating = self.retreating_edges();
+    let mut result: ~[Loop] = ~[];
+
+    // Outer loop headers precede inner ones in RPO.
+    //
+    // Every retreating edge closes a cycle, in reducible graphs these are
+    // exactly the back edges to dominating headers. Irreducible cycles are
+    // entered elsewhere too, and are approximated by the cycle through the
+    // header that DFS has found first.
+    for header in tree.order.iter() {
+      let mut ends = ~[];
+      for &(from, to) in retreating.iter() {
+        if to == *header { ends.push(from); }
+      }
+      if ends.len() == 0 { loop; }
+
+      // Loop body is everything reachable from the header and reaching
+      // ends without passing the header
+      let forward = self.reachable_from(header);
+      let mut blocks = ~[*header];
+      let mut visited = ~BitvSet::new();
+      let mut queue = ends.clone();
+      visited.insert(header.to_uint());
+      while queue.len() > 0 {
+        let cur = queue.pop();
+        if !forward.contains(&cur.to_uint()) ||
+           !visited.insert(cur.to_uint()) {
+          loop;
+        }
+        blocks.push(cur);
+        for pred in self.get_block(&cur).predecessors.iter() {
+          queue.push(*pred);
+        }
+      }
+
+      // Find innermost already known loop containing the header
+      let mut parent = None;
+      let mut i = result.len();
+      while i > 0 {
+        i -= 1;
+        if result[i].blocks.contains(header) {
+          parent = Some(i);
+          break;
+        }
+      }
+
+      let depth = match parent {
+        Some(p) => result[p].depth + 1,
+        None => 1
+      };
+      let reducible = do blocks.all |id| { tree.dominates(header, id) };
+      result.push(Loop {
+        header: *header,
+        ends: ends,
+        blocks: blocks,
+        parent: parent,
+        depth: depth,
+        reducible: reducible
+      });
+    }
+
+    return result;
+  }
+}
+
+impl DominatorTree {
+  /// Return true if block is reachable from root
+  pub fn is_reachable(&self, id: &BlockId) -> bool {
+    self.index.contains_key(&id.to_uint())
+  }
+
+  /// Return immediate dominator of block, or None for root and unreachable
+  /// blocks
+  pub fn idom(&self, id: &BlockId) -> Option<BlockId> {
+    match self.idom.find(&id.to_uint()) {
+      Some(idom) if idom != id => Some(*idom),
+      _ => None
+    }
+  }
+
+  /// Return true if `a` dominates `b` (every block dominates itself)
+  pub fn dominates(&self, a: &BlockId, b: &BlockId) -> bool {
+    if !self.is_reachable(a) || !self.is_reachable(b) {
+      return false;
+    }
+
+    // Dominators always precede blocks in reverse postorder
+    let a_index = *self.index.get(&a.to_uint());
+    let mut cur = *b;
+    while *self.index.get(&cur.to_uint()) > a_index {
+      cur = *self.idom.get(&cur.to_uint());
+    }
+    return cur == *a;
+  }
+
+  /// Find nearest common dominator of two blocks
+  fn intersect(&self, a: BlockId, b: BlockId) -> BlockId {
+    let mut a = a;
+    let mut b = b;
+    while a != b {
+      while self.index.get(&a.to_uint()) > self.index.get(&b.to_uint()) {
+        a = *self.idom.get(&a.to_uint());
+      }
+      while self.index.get(&b.to_uint()) > self.index.get(&a.to_uint()) {
+        b = *self.idom.get(&b.to_uint());
+      }
+    }
+    return a;
+  }
+}
@@ -1,7 +1,8 @@
 use extra::smallintmap::SmallIntMap;
 use extra::bitv::BitvSet;
-use linearscan::{KindHelper, RegisterHelper, GroupHelper};
-use linearscan::graph::{Graph, BlockId};
+use linearscan::{KindHelper, RegisterHelper, GroupHelper, GraphAPI};
+use linearscan::graph::{Graph, Block, BlockId, Phi, ToPhi};
+use linearscan::dominance::{Dominance};
 
 struct MapResult {
   block: BlockId,
@@ -14,12 +15,15 @@ pub trait Flatten {
 }
 
 trait FlattenHelper {
-  // Flatten CFG and detect/enumerate loops
-  //
-  // Get map: loop_start => [ loop ends ]
-  fn flatten_get_ends(&mut self) -> ~SmallIntMap<~[BlockId]>;
+  // Remove blocks unreachable from root and their instructions
+  fn flatten_prune(&mut self);
 
-  // Assign loop_index/loop_depth to each block
+  // Insert empty blocks on edges from blocks with multiple successors to
+  // blocks with multiple predecessors, so that movements resolving such edge
+  // won't be executed on other paths
+  fn split_critical_edges(&mut self);
+
+  // Detect loops and assign loop depth and index to every block
   fn flatten_assign_indexes(&mut self);
 
   // Assign new ids to blocks and instructions
@@ -30,74 +34,176 @@ trait FlattenHelper {
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R> > FlattenHelper for Graph<K, G, R> {
-  fn flatten_get_ends(&mut self) -> ~SmallIntMap<~[BlockId]> {
+  fn flatten_prune(&mut self) {
     let mut queue = ~[self.root.expect("Root block")];
-    let mut visited = ~BitvSet::new();
-    let mut ends: ~SmallIntMap<~[BlockId]> = ~SmallIntMap::new();
+    let mut reachable = ~BitvSet::new();
 
-    // Visit each block and find loop ends
+    // Find every reachable block
     while queue.len() > 0 {
-      let cur = queue.shift();
-      visited.insert(cur.to_uint());
+      let cur = queue.pop();
+      if !reachable.insert(cur.to_uint()) { loop; }
       for succ in self.get_block(&cur).successors.iter() {
-        if visited.contains(&succ.to_uint()) {
-          // Loop detected
-          if ends.contains_key(&succ.to_uint()) {
-            ends.find_mut(&succ.to_uint()).unwrap().push(cur);
-          } else {
-            ends.insert(succ.to_uint(), ~[cur]);
-          }
-        } else {
-          queue.push(*succ);
-        }
+        queue.push(*succ);
       }
     }
 
-    return ends;
-  }
-
-  fn flatten_assign_indexes(&mut self) {
-    let ends = self.flatten_get_ends();
-    let mut loop_index = 1;
-
-    for (&start, ends) in ends.iter() {
-      let start_id = BlockId(start);
-      let mut visited = ~BitvSet::new();
-      let mut queue = ~[];
-      let expected_depth = self.get_block(&start_id).loop_depth;
+    // Remove unreachable blocks with their instructions
+    let mut removed = ~[];
+    for id in self.get_block_list().iter() {
+      if reachable.contains(&id.to_uint()) { loop; }
 
-      // Decrement number of incoming forward branches
-      assert!(self.get_block(&start_id).incoming_forward_branches == 2);
-      self.get_mut_block(&start_id).incoming_forward_branches -= 1;
-
-      for end in ends.iter() {
-        queue.push(*end);
+      let block = self.blocks.pop(&id.to_uint()).expect("block");
+      for instr_id in block.instructions.iter() {
+        let instr = self.instructions.pop(&instr_id.to_uint()).unwrap();
+        self.removed_instructions.push(*instr_id);
+
+        // ToPhi's output is the interval of phi, which may be still alive
+        let mut intervals = instr.temporary.clone();
+        intervals.push_all(instr.extra_outputs);
+        match instr.kind {
+          ToPhi(_) => (),
+          _ => match instr.output {
+            Some(output) => intervals.push(output),
+            None => ()
+          }
+        }
+        for interval in intervals.iter() {
+          self.intervals.pop(&interval.to_uint());
+        }
+        removed.push_all(intervals);
       }
+      self.removed_blocks.push(*id);
+    }
 
-      while queue.len() > 0 {
-        let cur = queue.shift();
-        let block = self.get_mut_block(&cur);
+    // Phis might be hinted to use location of removed values
+    for (_, interval) in self.intervals.mut_iter() {
+      match interval.hint {
+        Some(hint) if removed.contains(&hint) => interval.hint = None,
+        _ => ()
+      }
+    }
 
-        // Skip visited blocks
-        if !visited.insert(cur.to_uint()) { loop; }
+    // Remove edges coming from unreachable blocks
+    for id in self.get_block_list().iter() {
+      let instructions = {
+        let block = self.get_mut_block(id);
+        let count = block.predecessors.len();
+        do block.predecessors.retain |pred| {
+          reachable.contains(&pred.to_uint())
+        };
+        block.incoming_forward_branches -= count - block.predecessors.len();
+        block.instructions.clone()
+      };
 
-        // Set depth and index of not-visited-yet nodes,
-        // if we're not visiting nested loop
-        if block.loop_depth == expected_depth {
-          block.loop_index = loop_index;
-          block.loop_depth += 1;
+      for instr_id in instructions.iter() {
+        let instr = self.get_mut_instr(instr_id);
+        match instr.kind {
+          Phi(_) => (),
+          _ => loop
+        };
+
+        let mut inputs = ~[];
+        let mut incoming = ~[];
+        for (i, pred) in instr.incoming.iter().enumerate() {
+          if reachable.contains(&pred.to_uint()) {
+            inputs.push(instr.inputs[i]);
+            incoming.push(*pred);
+          }
         }
+        instr.inputs = inputs;
+        instr.incoming = incoming;
+      }
+    }
 
-        // Enqueue predecessors if current is not a loop start
-        if cur.to_uint() != start {
-          for pred in block.predecessors.iter() {
-            queue.push(*pred);
+    // Remove ToPhis of unreachable blocks from phis
+    let phis = self.phis.clone();
+    for phi in phis.iter() {
+      let mut inputs = self.get_instr(phi).inputs.clone();
+      do inputs.retain |input| {
+        self.instructions.contains_key(&input.to_uint())
+      };
+      self.get_mut_instr(phi).inputs = inputs;
+    }
+  }
+
+  fn split_critical_edges(&mut self) {
+    for id in self.get_block_list().iter() {
+      let successors = self.get_block(id).successors.clone();
+      if successors.len() < 2 { loop; }
+
+      // Edges created for every successor, switch may have duplicate targets
+      let mut edges: ~[(BlockId, BlockId)] = ~[];
+      for (i, succ) in successors.iter().enumerate() {
+        if self.get_block(succ).predecessors.len() < 2 { loop; }
+
+        let mut edge = ~Block::new(self);
+        let edge_id = edge.id;
+        edge.add_successor(*succ);
+        edge.add_predecessor(*id);
+        edge.ended = true;
+        self.blocks.insert(edge_id.to_uint(), edge);
+        self.get_mut_block(id).successors[i] = edge_id;
+
+        // Previous edges to the same successor have already replaced their
+        // predecessor entries, so the first remaining one belongs to this edge
+        let instructions = {
+          let succ_block = self.get_mut_block(succ);
+          let pos = succ_block.predecessors.position_elem(id)
+                                           .expect("predecessor");
+          succ_block.predecessors[pos] = edge_id;
+          succ_block.instructions.clone()
+        };
+
+        // Move SSA phis' inputs to the new edge
+        for instr_id in instructions.iter() {
+          let instr = self.get_mut_instr(instr_id);
+          match instr.incoming.position_elem(id) {
+            Some(pos) => { instr.incoming[pos] = edge_id; },
+            None => {
+              // Single input may be shared by all edges from the block
+              let mut shared = None;
+              for (j, pred) in instr.incoming.iter().enumerate() {
+                if shared.is_none() &&
+                   edges.contains(&(*succ, *pred)) {
+                  shared = Some(j);
+                }
+              }
+              match shared {
+                Some(j) => {
+                  let input = instr.inputs[j];
+                  instr.inputs.push(input);
+                  instr.incoming.push(edge_id);
+                },
+                None => ()
+              }
+            }
           }
         }
+        edges.push((*succ, edge_id));
       }
+    }
+  }
 
-      // Increment loop index
-      loop_index += 1;
+  fn flatten_assign_indexes(&mut self) {
+    let loops = self.loops();
+
+    for (i, l) in loops.iter().enumerate() {
+      // Every loop end is a backward branch, the rest of predecessors
+      // are forward branches (root has none of them).
+      // Other entries of irreducible loops are treated as forward branches
+      // too, so their blocks are placed after every entry.
+      {
+        let header = self.get_mut_block(&l.header);
+        assert!(header.incoming_forward_branches >= l.ends.len());
+        header.incoming_forward_branches -= l.ends.len();
+      }
+
+      // Nested loops come later and will override depth and index
+      for id in l.blocks.iter() {
+        let block = self.get_mut_block(id);
+        block.loop_index = i + 1;
+        block.loop_depth = l.depth;
+      }
     }
   }
 
@@ -143,6 +249,18 @@ impl<G: GroupHelper<R>,
       self.blocks.insert(block.id.to_uint(), block);
     }
 
+    // Update predecessors of SSA phis' inputs
+    for id in result.iter() {
+      let instructions = self.get_block(id).instructions.clone();
+      for instr_id in instructions.iter() {
+        let instr = self.get_mut_instr(instr_id);
+        instr.incoming = do instr.incoming.map() |pred| {
+          *mapping.find(&pred.to_uint()).expect("phi predecessor")
+        };
+      }
+    }
+
+    self.renamed_blocks = mapping;
     return result;
   }
 
@@ -204,6 +322,7 @@ impl<G: GroupHelper<R>,
 
       // Update id
       phi.id = id;
+      self.phis[i] = id;
 
       // Queue phi
       queue.push(phi);
@@ -227,6 +346,8 @@ impl<G: GroupHelper<R>,
 
       self.instructions.insert(instr.id.to_uint(), instr);
     }
+
+    self.renamed_instructions = map;
   }
 }
 
@@ -234,6 +355,8 @@ impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R> > Flatten for Graph<K, G, R> {
   fn flatten(&mut self) {
+    self.flatten_prune();
+    self.split_critical_edges();
     self.flatten_assign_indexes();
 
     let mut queue = ~[self.root.expect("Root block")];
@@ -1,7 +1,7 @@
 use std::vec;
 use linearscan::*;
 use linearscan::graph::{Graph, InstrId, GapState, GapAction,
-                        Move, Swap};
+                        Move, Swap, Remat};
 
 #[deriving(Eq)]
 enum MoveStatus {
@@ -45,16 +45,28 @@ impl<G: GroupHelper<R>,
      K: KindHelper<G, R>+Clone> GapResolverHelper for Graph<K, G, R> {
   fn resolve_gap(&mut self, id: &InstrId) -> ~GapState {
     let state = self.gaps.pop(&id.to_uint()).unwrap();
-    let mut status = vec::from_elem(state.actions.len(), ToMove);
+
+    // Rematerializations have no source location, they're performed after
+    // every move that might read their destination
+    let mut moves = ~[];
+    let mut remats = ~[];
+    for action in state.actions.iter() {
+      match action.kind {
+        Remat(_) => remats.push(action.clone()),
+        _ => moves.push(action.clone())
+      }
+    }
+    let mut status = vec::from_elem(moves.len(), ToMove);
 
     let mut i = 0;
     let mut result = ~[];
-    while i < state.actions.len() {
+    while i < moves.len() {
       if status[i] == ToMove {
-        self.move_one(state.actions, i, status, &mut result);
+        self.move_one(moves, i, status, &mut result);
       }
       i += 1;
     }
+    result.push_all(remats);
     ~GapState { actions: result }
   }
 
@@ -1,19 +1,22 @@
 use linearscan::{KindHelper, RegisterHelper, GroupHelper};
+use linearscan::allocator::AllocatorResult;
 use linearscan::graph::{Graph, Value, InstrId, BlockId, Gap,
-                        Phi, ToPhi, User, Swap, Move};
+                        Phi, User, Swap, Move, Remat};
 
-pub trait Generator<K, G> {
-  fn generate(&self, g: &mut G);
+pub trait Generator<K, R, G> {
+  fn generate(&self, result: &AllocatorResult<R>, g: &mut G);
 }
 
 pub trait GeneratorFunctions<K, G: GroupHelper<R>, R: RegisterHelper<G> > {
-  /// Function prologue (stack initialization, etc)
-  fn prelude(&mut self);
+  /// Function prologue (stack initialization, etc), `saved` are callee-saved
+  /// registers used by the function, `spill_count` is a count of stack slots
+  /// in every group (the highest used slot id plus one)
+  fn prelude(&mut self, saved: &[R], spill_count: &[uint]);
 
-  /// Function epilogue.
+  /// Function epilogue, receives the same arguments as prologue.
   /// NOTE: might be invoked multiple times, called at the end of
   /// blocks without successors
-  fn epilogue(&mut self);
+  fn epilogue(&mut self, saved: &[R], spill_count: &[uint]);
 
   /// Swap `left` and `right` value
   fn swap(&mut self, left: &Value<G, R>, right: &Value<G, R>);
@@ -21,16 +24,20 @@ pub trait GeneratorFunctions<K, G: GroupHelper<R>, R: RegisterHelper<G> > {
   /// Move value from `from` to `to`
   fn move(&mut self, from: &Value<G, R>, to: &Value<G, R>);
 
+  /// Recompute value of rematerializable instruction into `to`
+  fn rematerialize(&mut self, kind: &K, to: &Value<G, R>);
+
   /// Block start notification, might be used to relocate labels
   fn block(&mut self, id: BlockId);
 
   /// Goto block
   fn goto(&mut self, id: BlockId);
 
-  /// Generate instruction
+  /// Generate instruction, `outputs` are in the same order as instruction's
+  /// result kinds
   fn instr(&mut self,
            kind: &K,
-           output: Option<Value<G,R> >,
+           outputs: &[Value<G, R>],
            inputs: &[Value<G, R>],
            temporary: &[Value<G, R>],
            succ: &[BlockId]);
@@ -43,10 +50,20 @@ pub trait GeneratorHelper<K, GF> {
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R>,
-     GF: GeneratorFunctions<K, G, R> > Generator<K, GF>
+     GF: GeneratorFunctions<K, G, R> > Generator<K, R, GF>
     for Graph<K, G, R> {
-  fn generate(&self, g: &mut GF) {
-    g.prelude();
+  fn generate(&self, result: &AllocatorResult<R>, g: &mut GF) {
+    let mut saved = ~[];
+    for regs in result.used_registers.iter() {
+      for reg in regs.iter() {
+        if reg.callee_saved() {
+          saved.push(reg.clone());
+        }
+      }
+    }
+    let spill_count = result.spill_count.as_slice();
+
+    g.prelude(saved, spill_count);
 
     // Invoke functions in order of increasing instruction id
     for (id, instr) in self.instructions.iter() {
@@ -68,54 +85,60 @@ impl<G: GroupHelper<R>,
         self.generate_gap(g, &InstrId(*id));
       }
 
-      // Non-gap instructions
-      if !is_gap {
-        // NOTE: call instruction's output is located right after instruction
-        let output = match instr.output {
-          Some(ref out) => {
-            let group = instr.kind.result_kind().unwrap().group();
-            self.get_value(out, if instr.kind.clobbers(&group) {
+      // Non-gap instructions, NOTE: ToPhi moves are stored in the gap state of
+      // the first ToPhi in a run
+      match instr.kind {
+        User(ref k) => {
+          // NOTE: call instruction's outputs are located right after
+          // instruction
+          let outputs = do self.get_outputs(&instr.id).map() |out| {
+            let group = self.get_interval(out).value.group();
+            self.get_value(out, if self.clobbers(&group, &instr.id) {
               instr.id.next()
             } else {
               instr.id
-            })
-          },
-          None => None
-        };
-        let inputs = do instr.inputs.map() |inp| {
-          self.get_value(&self.get_output(inp), instr.id).expect("input")
-        };
-        let temporary = do instr.temporary.map() |tmp| {
-          self.get_value(tmp, instr.id).expect("temporary")
-        };
-        match instr.kind {
-          Phi(_) => (),
-          ToPhi(_) => {
-            assert!(inputs.len() == 1);
-            let out = output.expect("ToPhi output");
-            if out != inputs[0] {
-              g.move(&inputs[0], &out);
+            }).expect("output")
+          };
+          // NOTE: two-address instruction reads input from output's location
+          let out_kinds = instr.kind.result_kinds();
+          let mut inputs = ~[];
+          for (i, inp) in instr.inputs.iter().enumerate() {
+            let mut value = None;
+            for (j, kind) in out_kinds.iter().enumerate() {
+              if kind.same_as() == Some(i) {
+                value = Some(outputs[j].clone());
+              }
             }
-          },
-          Gap => (), // handled separately
-          User(ref k) => g.instr(k,
-                                 output,
-                                 inputs,
-                                 temporary,
-                                 block.successors)
-        }
+            inputs.push(match value {
+              Some(value) => value,
+              None => self.get_value(&self.get_output(inp), instr.id)
+                          .expect("input")
+            });
+          }
+          // NOTE: call instruction's temporaries are located right before
+          // instruction
+          let temporary = do instr.temporary.map() |tmp| {
+            let group = self.get_interval(tmp).value.group();
+            self.get_value(tmp, if self.clobbers(&group, &instr.id) {
+              instr.id.prev()
+            } else {
+              instr.id
+            }).expect("temporary")
+          };
+          g.instr(k, outputs, inputs, temporary, block.successors);
+        },
+        _ => ()
       }
 
       // Handle last instruction
       if instr.id == block.end().prev() {
         match block.successors.len() {
-          0 => g.epilogue(),
+          0 => g.epilogue(saved, spill_count),
           1 => if block.successors[0].to_uint() != block.id.to_uint() + 1 {
             // Goto to non-consequent successor
             g.goto(block.successors[0])
           },
-          2 => (), // Should be handled in instruction
-          _ => fail!("Too much successors")
+          _ => () // Should be handled in instruction
         }
       }
     }
@@ -135,7 +158,11 @@ impl<G: GroupHelper<R>,
 
         match action.kind {
           Swap => g.swap(&from, &to),
-          Move => g.move(&from, &to)
+          Move => g.move(&from, &to),
+          Remat(def) => match self.get_instr(&def).kind {
+            User(ref k) => g.rematerialize(k, &to),
+            _ => fail!("Only user instructions are rematerializable")
+          }
         }
       },
       None => ()
@@ -3,9 +3,9 @@ use extra::bitv::BitvSet;
 use std::uint;
 use linearscan::{KindHelper, RegisterHelper, GroupHelper};
 
-#[deriving(Eq, Ord, Clone)]
+#[deriving(Eq, Ord, Clone, ToStr)]
 pub struct BlockId(uint);
-#[deriving(Eq, Ord, Clone)]
+#[deriving(Eq, Ord, Clone, ToStr)]
 pub struct InstrId(uint);
 #[deriving(Eq, Ord, Clone)]
 pub struct IntervalId(uint);
@@ -21,9 +21,19 @@ pub struct Graph<K, G, R> {
   blocks: ~SmallIntMap<~Block<K> >,
   instructions: ~SmallIntMap<~Instruction<K, G> >,
   phis: ~[InstrId],
+  // Locations of parameters, indexed by their output intervals
+  params: ~SmallIntMap<Value<G, R> >,
   gaps: ~SmallIntMap<~GapState>,
   prepared: bool,
-  physical: ~SmallIntMap<~SmallIntMap<IntervalId> >
+  physical: ~SmallIntMap<~SmallIntMap<IntervalId> >,
+  // Blocks and instructions unreachable from root (with ids from before
+  // flattening)
+  removed_blocks: ~[BlockId],
+  removed_instructions: ~[InstrId],
+  // New ids of blocks and instructions assigned by flattening, indexed by
+  // old ones
+  renamed_blocks: ~SmallIntMap<BlockId>,
+  renamed_instructions: ~SmallIntMap<InstrId>
 }
 
 // Trait for all ids
@@ -57,7 +67,11 @@ pub struct Instruction<K, G> {
   block: BlockId,
   kind: InstrKind<K, G>,
   output: Option<IntervalId>,
+  // Outputs of multi-output instruction, except the first one
+  extra_outputs: ~[IntervalId],
   inputs: ~[InstrId],
+  // Predecessor blocks of SSA phi's inputs (same order as `inputs`)
+  incoming: ~[BlockId],
   temporary: ~[IntervalId],
   added: bool
 }
@@ -69,7 +83,11 @@ pub enum InstrKind<K, G> {
   User(K),
   Gap,
   Phi(G),
-  ToPhi(G)
+  ToPhi(G),
+  // Handle for an extra output of the preceding multi-output instruction
+  Proj(G),
+  // Function parameter, defined at the start of the root block
+  Param(G)
 }
 
 pub struct Interval<G, R> {
@@ -100,7 +118,65 @@ pub struct Use<G, R> {
 pub enum UseKind<G, R> {
   UseAny(G),
   UseRegister(G),
-  UseFixed(R)
+  UseFixed(R),
+  // Value should be in any stack slot
+  UseStack(G),
+  // Value should be in the specified stack slot
+  UseFixedStack(G, StackId),
+  // Result kind only: output should be in the location of `i`th input
+  UseSameAs(G, uint)
+}
+
+// Moment when instruction is reading its input
+#[deriving(Eq, Clone)]
+pub enum UseTiming {
+  // Input may share location with the outputs
+  UseAtStart,
+  // Input is read after outputs are written
+  UseAtEnd
+}
+
+// Errors reported on incorrect graph construction
+#[deriving(Eq, Clone, ToStr)]
+pub enum GraphError {
+  AlreadyAdded(InstrId),
+  AlreadyEnded(BlockId),
+  EmptyBlock(BlockId),
+  NotPhi(InstrId),
+  NoOutput(InstrId),
+  NotInBlock(InstrId, BlockId),
+  UnknownBlock(BlockId),
+  UnknownInstr(InstrId),
+  NoRoot,
+  NotEnded(BlockId),
+  // Instruction's input isn't dominated by its definition
+  NotDominated(InstrId, InstrId),
+  // Instruction has unexpected number of inputs (expected, got)
+  WrongInputCount(InstrId, uint, uint),
+  // Group of instruction's input differs from the group of value
+  GroupMismatch(InstrId, uint),
+  // Instruction can't be removed, because it is used by other one
+  StillUsed(InstrId, InstrId),
+  // Instruction wasn't added to any block
+  NotAdded(InstrId),
+  // Graph can't be modified after allocation, see `reset_allocation`
+  AlreadyPrepared,
+  // Parameter's location should be a register or a stack slot
+  VirtualParam,
+  // Output (index) is the same as input, that instruction doesn't have
+  WrongSameAs(InstrId, uint),
+  // Instruction can't be moved between blocks (phi, parameter, ToPhi or
+  // projection)
+  NotMovable(InstrId),
+  // Location is already taken by another parameter
+  LocationTaken(InstrId),
+  // Projection can't be removed or used as an anchor separately from the
+  // instruction defining it
+  IsProjection(InstrId),
+  // Input (index) uses kind that is valid only for results
+  WrongUseKind(InstrId, uint),
+  // Temporary (index) can't have such kind
+  WrongTemporary(InstrId, uint)
 }
 
 #[deriving(Eq)]
@@ -116,7 +192,9 @@ pub struct GapState {
 #[deriving(Eq, Clone)]
 pub enum GapActionKind {
   Move,
-  Swap
+  Swap,
+  // Recompute value of the instruction instead of loading it from the stack
+  Remat(InstrId)
 }
 
 #[deriving(Clone)]
@@ -140,9 +218,14 @@ impl<G: GroupHelper<R>,
       blocks: ~SmallIntMap::new(),
       instructions: ~SmallIntMap::new(),
       phis: ~[],
+      params: ~SmallIntMap::new(),
       gaps: ~SmallIntMap::new(),
       prepared: false,
-      physical: ~SmallIntMap::new()
+      physical: ~SmallIntMap::new(),
+      removed_blocks: ~[],
+      removed_instructions: ~[],
+      renamed_blocks: ~SmallIntMap::new(),
+      renamed_instructions: ~SmallIntMap::new()
     }
   }
 
@@ -154,7 +237,9 @@ impl<G: GroupHelper<R>,
       block: *block,
       kind: Gap,
       output: None,
+      extra_outputs: ~[],
       inputs: ~[],
+      incoming: ~[],
       temporary: ~[],
       added: true
     };
@@ -178,6 +263,34 @@ impl<G: GroupHelper<R>,
     return blocks;
   }
 
+  /// Return registers of every group that are used by allocated intervals
+  pub fn used_registers(&self) -> ~[~[R]] {
+    let groups: ~[G] = GroupHelper::groups();
+    let mut used = do groups.map() |_| { ~BitvSet::new() };
+    for (_, interval) in self.intervals.iter() {
+      // Physical intervals are only blocking registers
+      if interval.fixed || interval.ranges.len() == 0 {
+        loop;
+      }
+      match interval.value {
+        RegisterVal(ref r) => {
+          used[r.group().to_uint()].insert(r.to_uint());
+        },
+        _ => ()
+      }
+    }
+
+    let mut result = ~[];
+    for group in groups.iter() {
+      let mut regs = ~[];
+      for i in used[group.to_uint()].iter() {
+        regs.push(RegisterHelper::from_uint(group, i));
+      }
+      result.push(regs);
+    }
+    return result;
+  }
+
   /// Mutable instruction getter
   pub fn get_mut_instr<'r>(&'r mut self,
                            id: &InstrId) -> &'r mut ~Instruction<K, G> {
@@ -190,7 +303,68 @@ impl<G: GroupHelper<R>,
 
   /// Instruction output getter
   pub fn get_output(&self, id: &InstrId) -> IntervalId {
-    self.instructions.get(&id.to_uint()).output.expect("Instruction output")
+    expect_ok(self.try_get_output(id))
+  }
+
+  pub fn try_get_output(&self, id: &InstrId) -> Result<IntervalId, GraphError> {
+    match self.instructions.find(&id.to_uint()) {
+      Some(instr) => match instr.output {
+        Some(output) => Ok(output),
+        None => Err(NoOutput(*id))
+      },
+      None => Err(UnknownInstr(*id))
+    }
+  }
+
+  /// Return all output intervals of instruction
+  pub fn get_outputs(&self, id: &InstrId) -> ~[IntervalId] {
+    let instr = self.get_instr(id);
+    let mut res = ~[];
+    match instr.output {
+      Some(output) => res.push(output),
+      None => ()
+    }
+    res.push_all(instr.extra_outputs);
+    return res;
+  }
+
+  /// Return pairs of (input, phi output) intervals for every SSA phi of
+  /// `succ` that receives value from `pred`
+  pub fn get_phi_moves(&self,
+                       pred: &BlockId,
+                       succ: &BlockId) -> ~[(IntervalId, IntervalId)] {
+    let mut res = ~[];
+    for id in self.get_block(succ).instructions.iter() {
+      let instr = self.get_instr(id);
+      match instr.kind {
+        Phi(_) => (),
+        _ => loop
+      };
+      for (i, incoming) in instr.incoming.iter().enumerate() {
+        if incoming == pred {
+          res.push((self.get_output(&instr.inputs[i]), self.get_output(id)));
+        }
+      }
+    }
+    return res;
+  }
+
+  /// Return id of the first ToPhi in the run of consecutive ToPhis containing
+  /// `id`, gaps between them are ignored.
+  /// NOTE: the whole run is a single parallel move
+  pub fn to_phi_run_start(&self, id: &InstrId) -> InstrId {
+    let start = self.get_block(&self.get_instr(id).block).start();
+    let mut res = *id;
+    let mut cur = *id;
+    while cur > start {
+      cur = cur.prev();
+      match self.get_instr(&cur).kind {
+        Gap => (),
+        ToPhi(_) => { res = cur; },
+        _ => break
+      }
+    }
+    return res;
   }
 
   /// Mutable interval getter
@@ -337,7 +511,7 @@ impl<G: GroupHelper<R>,
 
     // Move out uses
     let mut child_uses =  ~[];
-    let split_on_call = self.get_instr(&pos).kind.clobbers(&group);
+    let split_on_call = self.clobbers(&group, &pos);
 
     // XXX: Wait for rust bug to be fixed and use filter_mapped
     let mut parent_uses = self.get_interval(&split_parent).uses.clone();
@@ -440,10 +614,18 @@ impl<G: GroupHelper<R>,
     }
   }
 
+  /// Return true if instruction's output could be recomputed instead of
+  /// being loaded from the spill slot
+  pub fn is_rematerializable(&self, id: &InstrId) -> bool {
+    let instr = self.get_instr(id);
+    return instr.inputs.len() == 0 && instr.extra_outputs.len() == 0 &&
+           instr.kind.rematerializable();
+  }
+
   /// Return true if instruction at specified position contains
-  /// register-clobbering call.
+  /// call clobbering some of group's registers.
   pub fn clobbers(&self, group: &G, pos: &InstrId) -> bool {
-    return self.get_instr(pos).kind.clobbers(group);
+    return self.get_instr(pos).kind.clobbers(group).len() != 0;
   }
 
   /// Return next block id, used at graph construction
@@ -491,13 +673,11 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G>, K: KindHelper<G, R> > Block<K> {
   }
 
   pub fn add_successor<'r>(&'r mut self, succ: BlockId) -> &'r mut Block<K> {
-    assert!(self.successors.len() <= 2);
     self.successors.push(succ);
     return self;
   }
 
   pub fn add_predecessor(&mut self, pred: BlockId) {
-    assert!(self.predecessors.len() <= 2);
     self.predecessors.push(pred);
     // NOTE: we'll decrease them later in flatten.rs
     self.incoming_forward_branches += 1;
@@ -514,8 +694,8 @@ impl<G: GroupHelper<R>,
     let id = graph.instr_id();
 
     let mut temporary = ~[];
-    for group in kind.temporary().iter() {
-      temporary.push(Interval::new(graph, group.clone()));
+    for k in kind.temporary().iter() {
+      temporary.push(Interval::new(graph, k.group()));
     }
 
     let r = Instruction {
@@ -523,7 +703,9 @@ impl<G: GroupHelper<R>,
       block: BlockId(0), // NOTE: this will be overwritten soon
       kind: kind,
       output: None,
+      extra_outputs: ~[],
       inputs: args.clone(),
+      incoming: ~[],
       temporary: temporary,
       added: false
     };
@@ -536,13 +718,16 @@ impl<G: GroupHelper<R>,
              kind: InstrKind<K, G>,
              args: ~[InstrId]) -> InstrId {
 
-    let output = match kind.result_kind() {
-      Some(k) => Some(Interval::new(graph, k.group())),
-      None => None
-    };
+    let mut outputs = ~[];
+    for k in kind.result_kinds().iter() {
+      outputs.push(Interval::new(graph, k.group()));
+    }
 
     let instr = Instruction::new_empty(graph, kind, args);
-    graph.get_mut_instr(&instr).output = output;
+    if outputs.len() > 0 {
+      graph.get_mut_instr(&instr).output = Some(outputs[0]);
+      graph.get_mut_instr(&instr).extra_outputs = outputs.tail().to_owned();
+    }
     return instr;
   }
 }
@@ -628,7 +813,7 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > Interval<G, R> {
   /// Return next UseFixed(...) or UseRegister after `after` position.
   pub fn next_use(&self, after: InstrId) -> Option<Use<G, R> > {
     for u in self.uses.iter() {
-      if u.pos >= after && !u.kind.is_any() {
+      if u.pos >= after && u.kind.is_register() {
         return Some(u.clone());
       }
     };
@@ -638,34 +823,98 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > Interval<G, R> {
   /// Return last UseFixed(...) or UseRegister before `before` position
   pub fn last_use(&self, before: InstrId) -> Option<Use<G, R> > {
     for u in self.uses.rev_iter() {
-      if u.pos <= before && !u.kind.is_any() {
+      if u.pos <= before && u.kind.is_register() {
         return Some(u.clone());
       }
     };
     return None;
   }
+
+  /// Return first UseFixedStack(...), or first UseStack if there're no
+  /// uses of fixed stack slots
+  pub fn stack_use(&self) -> Option<Use<G, R> > {
+    let mut result = None;
+    for u in self.uses.iter() {
+      match u.kind {
+        UseFixedStack(_, _) => { return Some(u.clone()); },
+        UseStack(_) if result.is_none() => { result = Some(u.clone()); },
+        _ => ()
+      }
+    };
+    return result;
+  }
 }
 
 impl<G: GroupHelper<R>,
      R: RegisterHelper<G>,
      K: KindHelper<G, R> > KindHelper<G, R> for InstrKind<K, G> {
-  /// Return true if instruction is clobbering registers
-  pub fn clobbers(&self, group: &G) -> bool {
+  /// Return registers clobbered by instruction
+  pub fn clobbers(&self, group: &G) -> ~[R] {
     match self {
       &User(ref k) => k.clobbers(group),
-      &Gap => false,
-      &ToPhi(_) => false,
-      &Phi(_) => false
+      &Gap => ~[],
+      &ToPhi(_) => ~[],
+      &Phi(_) => ~[],
+      &Proj(_) => ~[],
+      &Param(_) => ~[]
     }
   }
 
-  /// Return count of instruction's temporary operands
-  pub fn temporary(&self) -> ~[G] {
+  /// Return use kinds of instruction's temporary operands
+  pub fn temporary(&self) -> ~[UseKind<G, R>] {
     match self {
       &User(ref k) => k.temporary(),
       &Gap => ~[],
       &Phi(_) => ~[],
-      &ToPhi(_) => ~[]
+      &ToPhi(_) => ~[],
+      &Proj(_) => ~[],
+      &Param(_) => ~[]
+    }
+  }
+
+  /// Return count of instruction's inputs, if it is known
+  /// NOTE: phis have variable number of inputs
+  pub fn input_count(&self) -> Option<uint> {
+    match self {
+      &User(ref k) => k.input_count(),
+      &Gap => Some(0),
+      &Phi(_) => None,
+      &ToPhi(_) => Some(1),
+      &Proj(_) => Some(1),
+      &Param(_) => Some(0)
+    }
+  }
+
+  /// Return moment when instruction's `i`th input is read
+  pub fn use_timing(&self, i: uint) -> UseTiming {
+    match self {
+      &User(ref k) => k.use_timing(i),
+      &Gap => fail!("Gap can't have any input"),
+      &Param(_) => fail!("Param can't have any input"),
+      &Phi(_) => UseAtStart,
+      &ToPhi(_) => UseAtStart,
+      &Proj(_) => UseAtStart
+    }
+  }
+
+  /// Return true if instruction's `i`th output is written before inputs are
+  /// read
+  pub fn early_clobber(&self, i: uint) -> bool {
+    match self {
+      &User(ref k) => k.early_clobber(i),
+      &Gap => false,
+      &Phi(_) => false,
+      &ToPhi(_) => false,
+      &Proj(_) => false,
+      &Param(_) => false
+    }
+  }
+
+  /// Return true if instruction is cheap to recompute
+  pub fn rematerializable(&self) -> bool {
+    match self {
+      &User(ref k) => k.rematerializable(),
+      _ => false
     }
   }
 
@@ -674,18 +923,23 @@ impl<G: GroupHelper<R>,
     match self {
       &User(ref k) => k.use_kind(i),
       &Gap => fail!("Gap can't have any input"),
+      &Param(_) => fail!("Param can't have any input"),
       &Phi(ref g) => UseAny(g.clone()),
-      &ToPhi(ref g) => UseAny(g.clone())
+      &ToPhi(ref g) => UseAny(g.clone()),
+      &Proj(ref g) => UseAny(g.clone())
     }
   }
 
-  /// Return result kind of instruction or None, if instruction has no result
-  pub fn result_kind(&self) -> Option<UseKind<G, R> > {
+  /// Return result kinds of every instruction's output
+  pub fn result_kinds(&self) -> ~[UseKind<G, R>] {
     match self {
-      &User(ref k) => k.result_kind(),
-      &Gap => None,
-      &Phi(ref g) => Some(UseAny(g.clone())),
-      &ToPhi(ref g) => Some(UseAny(g.clone()))
+      &User(ref k) => k.result_kinds(),
+      &Gap => ~[],
+      &Phi(ref g) => ~[UseAny(g.clone())],
+      &ToPhi(ref g) => ~[UseAny(g.clone())],
+      &Proj(ref g) => ~[UseAny(g.clone())],
+      // NOTE: actual location is stored in graph's `params`
+      &Param(ref g) => ~[UseAny(g.clone())]
     }
   }
 }
@@ -739,11 +993,38 @@ impl<G: GroupHelper<R>, R: RegisterHelper<G> > UseKind<G, R> {
     }
   }
 
+  /// Return true if use requires value to be in register
+  pub fn is_register(&self) -> bool {
+    match self {
+      &UseRegister(_) | &UseFixed(_) => true,
+      _ => false
+    }
+  }
+
+  /// Return true if use requires value to be in stack slot
+  pub fn is_stack(&self) -> bool {
+    match self {
+      &UseStack(_) | &UseFixedStack(_, _) => true,
+      _ => false
+    }
+  }
+
   pub fn group(&self) -> G {
     match self {
       &UseRegister(ref g) => g.clone(),
       &UseAny(ref g) => g.clone(),
       &UseFixed(ref r) => r.group(),
+      &UseStack(ref g) => g.clone(),
+      &UseFixedStack(ref g, _) => g.clone(),
+      &UseSameAs(ref g, _) => g.clone()
+    }
+  }
+
+  /// Return index of input which location should be reused by output
+  pub fn same_as(&self) -> Option<uint> {
+    match self {
+      &UseSameAs(_, i) => Some(i),
+      _ => None
     }
   }
 }
@@ -766,6 +1047,14 @@ impl<K> Block<K> {
   }
 }
 
+/// Return result's value or fail with the description of error
+pub fn expect_ok<T>(res: Result<T, GraphError>) -> T {
+  match res {
+    Ok(r) => r,
+    Err(err) => fail!(err.to_str())
+  }
+}
+
 // Implement trait for ids
 impl GraphId for BlockId {
   fn to_uint(&self) -> uint { match self { &BlockId(id) => id } }
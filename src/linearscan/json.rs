@@ -2,8 +2,10 @@ use extra::json::{ToJson, Json, Object, List, String, Number, Boolean, Null};
 use std::hashmap::HashMap;
 use linearscan::{KindHelper, GroupHelper, RegisterHelper};
 use linearscan::graph::{Graph, Block, Instruction, Interval, LiveRange,
-                        User, Gap, GapState, Move, Swap, ToPhi, Phi,
-                        Use, UseAny, UseRegister, UseFixed,
+                        User, Gap, GapState, Move, Swap, Remat, ToPhi, Phi,
+                        Proj, Param,
+                        Use, UseAny, UseRegister, UseFixed, UseStack,
+                        UseFixedStack, UseSameAs,
                         Value, VirtualVal, RegisterVal, StackVal};
 
 trait JsonHelper {
@@ -43,7 +45,9 @@ impl<G: GroupHelper<R>+ToStr,
       User(ref kind) => kind.to_str(),
       Gap => ~"~gap",
       ToPhi(_) => ~"~to_phi",
-      Phi(_) => ~"~phi"
+      Phi(_) => ~"~phi",
+      Proj(_) => ~"~proj",
+      Param(_) => ~"~param"
     }));
     obj.insert(~"inputs", List(do self.inputs.map() |input| {
       Number(input.to_uint() as float)
@@ -55,6 +59,9 @@ impl<G: GroupHelper<R>+ToStr,
       Some(output) => Number(output.to_uint() as float),
       None => Null
     });
+    obj.insert(~"extra_outputs", List(do self.extra_outputs.map() |o| {
+      Number(o.to_uint() as float)
+    }));
 
     return Object(obj);
   }
@@ -68,7 +75,8 @@ impl ToJson for GapState {
       let mut obj = ~HashMap::new();
       obj.insert(~"type", String(match act.kind {
         Move => ~"move",
-        Swap => ~"swap"
+        Swap => ~"swap",
+        Remat(_) => ~"remat"
       }));
       obj.insert(~"from", Number(act.from.to_uint() as float));
       obj.insert(~"to", Number(act.to.to_uint() as float));
@@ -123,6 +131,15 @@ impl<G: GroupHelper<R>+ToStr,
       UseFixed(ref val) => {
         kind.insert(~"type", String(~"fixed"));
         kind.insert(~"value", String(val.to_str()))
+      },
+      UseStack(_) => kind.insert(~"type", String(~"stack")),
+      UseFixedStack(_, slot) => {
+        kind.insert(~"type", String(~"fixed_stack"));
+        kind.insert(~"value", Number(slot.to_uint() as float))
+      },
+      UseSameAs(_, i) => {
+        kind.insert(~"type", String(~"same_as"));
+        kind.insert(~"value", Number(i as float))
       }
     };
     obj.insert(~"group", Number(self.kind.group().to_uint() as float));
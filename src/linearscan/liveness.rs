@@ -1,5 +1,5 @@
 use linearscan::{KindHelper, RegisterHelper, GroupHelper};
-use linearscan::graph::{Graph, BlockId};
+use linearscan::graph::{Graph, BlockId, Phi, ToPhi, Proj};
 use extra::bitv::BitvSet;
 
 pub trait Liveness {
@@ -30,20 +30,40 @@ impl<G: GroupHelper<R>,
   fn build_local(&mut self, blocks: &[BlockId]) {
     for block in blocks.iter() {
       let instructions = self.get_block(block).instructions.clone();
+      let mut run_kill = ~BitvSet::new();
 
       for instr in instructions.iter() {
-        let output = self.get_instr(instr).output;
+        let outputs = self.get_outputs(instr);
         let inputs = self.get_instr(instr).inputs.clone();
 
-        match output {
-          Some(output) => self.get_mut_block(block).live_kill
-                              .insert(output.to_uint()),
-          None => true
+        // ToPhis in a run are reading inputs before any output is written
+        let is_to_phi = match self.get_instr(instr).kind {
+          ToPhi(_) => true,
+          _ => false
+        };
+        if is_to_phi && self.to_phi_run_start(instr) == *instr {
+          run_kill = self.get_block(block).live_kill.clone();
+        }
+
+        for output in outputs.iter() {
+          self.get_mut_block(block).live_kill.insert(output.to_uint());
+        }
+
+        // SSA phi's inputs are live at the end of predecessors, and
+        // projections aren't reading their instruction
+        match self.get_instr(instr).kind {
+          Phi(_) | Proj(_) => loop,
+          _ => ()
         };
 
         for input_instr in inputs.iter() {
           let input = self.get_output(input_instr);
-          if !self.get_block(block).live_kill.contains(&input.to_uint()) {
+          let killed = if is_to_phi {
+            run_kill.contains(&input.to_uint())
+          } else {
+            self.get_block(block).live_kill.contains(&input.to_uint())
+          };
+          if !killed {
             self.get_mut_block(block).live_gen.insert(input.to_uint());
           }
         }
@@ -62,6 +82,11 @@ impl<G: GroupHelper<R>,
         let mut tmp = ~BitvSet::new();
         for succ in successors.iter() {
           tmp.union_with(self.get_block(succ).live_in);
+
+          // Inputs of successor's phis are live out too
+          for &(input, _) in self.get_phi_moves(block, succ).iter() {
+            tmp.insert(input.to_uint());
+          }
         }
 
         // Propagate succ.live_in to block.live_out
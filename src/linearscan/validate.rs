@@ -0,0 +1,196 @@
+use extra::smallintmap::SmallIntMap;
+use std::uint;
+use linearscan::{KindHelper, RegisterHelper, GroupHelper};
+use linearscan::graph::{Graph, BlockId, InstrId, GraphError,
+                        User, Phi, ToPhi, Proj, Param, Gap,
+                        NoRoot, NotEnded, NotDominated, WrongInputCount,
+                        GroupMismatch, UnknownInstr, WrongSameAs,
+                        WrongUseKind, WrongTemporary};
+use linearscan::dominance::{Dominance, DominatorTree};
+
+pub trait Validate {
+  // Check graph before allocation, return every found error
+  fn validate(&self) -> Result<(), ~[GraphError]>;
+}
+
+trait ValidateHelper {
+  // Check inputs of instruction at `pos` in block
+  fn validate_instr(&self,
+                    id: &InstrId,
+                    pos: uint,
+                    index: &SmallIntMap<uint>,
+                    dominators: &Option<~DominatorTree>,
+                    errors: &mut ~[GraphError]);
+
+  // Return true if `def`'s output is available at `pos` in block
+  fn is_available(&self,
+                  def: &InstrId,
+                  block: &BlockId,
+                  pos: uint,
+                  index: &SmallIntMap<uint>,
+                  dominators: &Option<~DominatorTree>) -> bool;
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > Validate for Graph<K, G, R> {
+  fn validate(&self) -> Result<(), ~[GraphError]> {
+    let mut errors = ~[];
+
+    for (_, block) in self.blocks.iter() {
+      if !block.ended {
+        errors.push(NotEnded(block.id));
+      }
+    }
+
+    let dominators = match self.root {
+      Some(_) => Some(self.dominators()),
+      None => {
+        errors.push(NoRoot);
+        None
+      }
+    };
+
+    // Position of every instruction in its block
+    let mut index = ~SmallIntMap::new();
+    for (_, block) in self.blocks.iter() {
+      for (i, id) in block.instructions.iter().enumerate() {
+        index.insert(id.to_uint(), i);
+      }
+    }
+
+    for (_, block) in self.blocks.iter() {
+      for (i, id) in block.instructions.iter().enumerate() {
+        self.validate_instr(id, i, index, &dominators, &mut errors);
+      }
+    }
+
+    return if errors.len() == 0 { Ok(()) } else { Err(errors) };
+  }
+}
+
+impl<G: GroupHelper<R>,
+     R: RegisterHelper<G>,
+     K: KindHelper<G, R> > ValidateHelper for Graph<K, G, R> {
+  fn validate_instr(&self,
+                    id: &InstrId,
+                    pos: uint,
+                    index: &SmallIntMap<uint>,
+                    dominators: &Option<~DominatorTree>,
+                    errors: &mut ~[GraphError]) {
+    let instr = self.get_instr(id);
+
+    match instr.kind {
+      User(ref k) => match k.input_count() {
+        Some(count) if count != instr.inputs.len() => {
+          errors.push(WrongInputCount(*id, count, instr.inputs.len()));
+        },
+        _ => ()
+      },
+      Gap | Param(_) if instr.inputs.len() != 0 => {
+        errors.push(WrongInputCount(*id, 0, instr.inputs.len()));
+      },
+      _ => ()
+    }
+
+    // Two-address outputs should refer to existing inputs, and only outputs
+    // could be the same as input
+    match instr.kind {
+      User(ref k) => {
+        for (i, kind) in k.result_kinds().iter().enumerate() {
+          match kind.same_as() {
+            Some(input) if input >= instr.inputs.len() => {
+              errors.push(WrongSameAs(*id, i));
+            },
+            _ => ()
+          }
+        }
+        for (i, _) in instr.inputs.iter().enumerate() {
+          if k.use_kind(i).same_as().is_some() {
+            errors.push(WrongUseKind(*id, i));
+          }
+        }
+        // Temporaries are scratch registers
+        for (i, kind) in k.temporary().iter().enumerate() {
+          if kind.same_as().is_some() || kind.is_stack() {
+            errors.push(WrongTemporary(*id, i));
+          }
+        }
+      },
+      _ => ()
+    }
+
+    for (i, input) in instr.inputs.iter().enumerate() {
+      if !self.instructions.contains_key(&input.to_uint()) {
+        errors.push(UnknownInstr(*input));
+        loop;
+      }
+
+      let output = match self.try_get_output(input) {
+        Ok(output) => output,
+        Err(err) => {
+          errors.push(err);
+          loop;
+        }
+      };
+
+      // Phi's inputs should be available at the end of predecessor
+      let available = match instr.kind {
+        Phi(_) => self.is_available(input,
+                                    &instr.incoming[i],
+                                    uint::max_value,
+                                    index,
+                                    dominators),
+        _ => self.is_available(input, &instr.block, pos, index, dominators)
+      };
+      if !available {
+        errors.push(NotDominated(*id, *input));
+      }
+
+      let group = match instr.kind {
+        User(ref k) => k.use_kind(i).group(),
+        Phi(ref g) => g.clone(),
+        ToPhi(ref g) => g.clone(),
+        // Projection's input is the instruction defining it
+        Proj(_) => loop,
+        // Reported above
+        Gap | Param(_) => loop
+      };
+      if group != self.get_interval(&output).value.group() {
+        errors.push(GroupMismatch(*id, i));
+      }
+    }
+  }
+
+  fn is_available(&self,
+                  def: &InstrId,
+                  block: &BlockId,
+                  pos: uint,
+                  index: &SmallIntMap<uint>,
+                  dominators: &Option<~DominatorTree>) -> bool {
+    // Unreachable code can't observe anything
+    match *dominators {
+      Some(ref d) if !d.is_reachable(block) => { return true; },
+      _ => ()
+    }
+
+    // Phis are defined by ToPhi movements
+    if self.phis.contains(def) {
+      return true;
+    }
+
+    let def_pos = match index.find(&def.to_uint()) {
+      Some(def_pos) => *def_pos,
+      None => { return false; } // Not added to any block
+    };
+
+    let def_block = self.get_instr(def).block;
+    if def_block == *block {
+      return def_pos < pos;
+    }
+    return match *dominators {
+      Some(ref d) => d.dominates(&def_block, block),
+      None => true
+    };
+  }
+}
@@ -5,14 +5,28 @@ use extra::smallintmap::SmallIntMap;
 pub enum Kind {
   Increment,
   Sum,
+  InplaceSum,
+  Accumulate,
+  EarlySum,
   DoubleSum,
   MultAdd,
+  MemSum,
+  Cpuid,
+  DivMod,
   BranchIfBigger,
+  Switch,
   JustUse,
   FixedUse,
+  StackUse,
+  StackPair,
+  // Kinds of inputs and temporaries are rejected by validation
+  Invalid,
   Nop,
   Print,
+  IndirectPrint,
   Number(uint),
+  Constant(uint),
+  StackArg(uint),
   DoubleNumber(float),
   ToDouble,
   Return,
@@ -78,32 +92,65 @@ impl RegisterHelper<Group> for Register {
       }
     }
   }
+
+  fn aliases(&self) -> ~[Register] {
+    // Lower half of xmm4 is shared with rdx
+    match *self {
+      rdx => ~[xmm4],
+      xmm4 => ~[rdx],
+      _ => ~[]
+    }
+  }
+
+  fn callee_saved(&self) -> bool {
+    *self == rbx
+  }
 }
 
 impl KindHelper<Group, Register> for Kind {
-  fn clobbers(&self, _: &Group) -> bool {
-    match self {
-      &Print => true,
-      _ => false
+  fn clobbers(&self, group: &Group) -> ~[Register] {
+    match (self, group) {
+      // rbx is preserved across calls
+      (&Print, &Normal) | (&IndirectPrint, &Normal) => ~[rax, rcx, rdx],
+      (&Print, &Double) | (&IndirectPrint, &Double) => group.registers(),
+      _ => ~[]
     }
   }
 
-  fn temporary(&self) -> ~[Group] {
+  fn temporary(&self) -> ~[UseKind<Group, Register>] {
     match self {
-      &BranchIfBigger => ~[Normal],
+      &BranchIfBigger | &IndirectPrint => ~[Normal.use_reg()],
+      &Cpuid => ~[rcx.use_fixed(), rdx.use_fixed()],
+      &Invalid => ~[Normal.use_same_as(0), Normal.use_stack()],
       _ => ~[]
     }
   }
 
+  fn input_count(&self) -> Option<uint> {
+    Some(match self {
+      &Nop | &Cpuid | &Number(_) | &Constant(_) | &StackArg(_) |
+      &DoubleNumber(_) => 0,
+      &Sum | &InplaceSum | &Accumulate | &EarlySum | &MemSum |
+      &DoubleSum | &DivMod | &BranchIfBigger | &StackPair => 2,
+      &MultAdd => 3,
+      &FixedUse => 4,
+      _ => 1
+    })
+  }
+
   fn use_kind(&self, i: uint) -> UseKind<Group, Register> {
     match self {
       &BranchIfBigger if i == 0 => rcx.use_fixed(),
+      &MemSum if i == 0 => Normal.use_reg(),
+      &MemSum => Normal.use_stack(),
+      &StackUse | &StackPair => Normal.use_fixed_stack(StackId(0)),
+      &Invalid => Normal.use_same_as(0),
       &JustUse => rbx.use_fixed(),
       &FixedUse => {
         let r: Register = RegisterHelper::from_uint(&Normal, i);
         r.use_fixed()
       },
-      &Print => rdx.use_fixed(),
+      &Print | &IndirectPrint => rdx.use_fixed(),
       &Return => rax.use_fixed(),
       &ReturnDouble => xmm1.use_fixed(),
       &DoubleSum => Double.use_reg(),
@@ -112,18 +159,47 @@ impl KindHelper<Group, Register> for Kind {
     }
   }
 
-  fn result_kind(&self) -> Option<UseKind<Group, Register> > {
+  fn use_timing(&self, i: uint) -> UseTiming {
+    match self {
+      &Accumulate if i == 1 => UseAtEnd,
+      _ => UseAtStart
+    }
+  }
+
+  fn result_kinds(&self) -> ~[UseKind<Group, Register>] {
+    match self {
+      &Return => ~[],
+      &ReturnDouble => ~[],
+      &BranchIfBigger => ~[],
+      &Switch => ~[],
+      &JustUse => ~[],
+      &FixedUse => ~[],
+      &StackUse => ~[],
+      &StackPair => ~[],
+      &Invalid => ~[],
+      &Nop => ~[],
+      &DivMod => ~[rax.use_fixed(), rdx.use_fixed()],
+      &InplaceSum => ~[Normal.use_same_as(0)],
+      &StackArg(n) => ~[Normal.use_fixed_stack(StackId(n))],
+      &Constant(_) => ~[Normal.use_any()],
+      &DoubleNumber(_) => ~[Double.use_any()],
+      &DoubleSum => ~[Double.use_reg()],
+      &ToDouble => ~[Double.use_reg()],
+      _ => ~[Normal.use_reg()]
+    }
+  }
+
+  fn early_clobber(&self, _: uint) -> bool {
+    match self {
+      &EarlySum => true,
+      _ => false
+    }
+  }
+
+  fn rematerializable(&self) -> bool {
     match self {
-      &Return => None,
-      &ReturnDouble => None,
-      &BranchIfBigger => None,
-      &JustUse => None,
-      &FixedUse => None,
-      &Nop => None,
-      &DoubleNumber(_) => Some(Double.use_any()),
-      &DoubleSum => Some(Double.use_reg()),
-      &ToDouble => Some(Double.use_reg()),
-      _ => Some(Normal.use_reg())
+      &Number(_) | &Constant(_) => true,
+      _ => false
     }
   }
 }
@@ -136,7 +212,9 @@ pub struct Emulator {
   registers: ~SmallIntMap<uint>,
   double_registers: ~SmallIntMap<float>,
   stack: ~SmallIntMap<uint>,
-  double_stack: ~SmallIntMap<float>
+  double_stack: ~SmallIntMap<float>,
+  rematerialized: uint,
+  stores: uint
 }
 
 #[deriving(Clone)]
@@ -152,18 +230,18 @@ enum Instruction {
 #[deriving(Clone)]
 struct GenericInstruction {
   kind: Kind,
-  output: Option<Value<Group, Register>>,
+  outputs: ~[Value<Group, Register>],
   inputs: ~[Value<Group, Register>],
   temporary: ~[Value<Group, Register>],
   succ: ~[BlockId]
 }
 
 impl GeneratorFunctions<Kind, Group, Register> for Emulator {
-  fn prelude(&mut self) {
+  fn prelude(&mut self, _: &[Register], _: &[uint]) {
     // nop
   }
 
-  fn epilogue(&mut self) {
+  fn epilogue(&mut self, _: &[Register], _: &[uint]) {
     self.instructions.push(UnexpectedEnd);
   }
 
@@ -176,9 +254,24 @@ impl GeneratorFunctions<Kind, Group, Register> for Emulator {
   fn move(&mut self,
           from: &Value<Group, Register>,
           to: &Value<Group, Register>) {
+    match *to {
+      StackVal(_, _) => self.stores += 1,
+      _ => ()
+    }
     self.instructions.push(Move(from.clone(), to.clone()));
   }
 
+  fn rematerialize(&mut self, kind: &Kind, to: &Value<Group, Register>) {
+    self.rematerialized += 1;
+    self.instructions.push(Generic(GenericInstruction {
+      kind: *kind,
+      outputs: ~[to.clone()],
+      inputs: ~[],
+      temporary: ~[],
+      succ: ~[]
+    }));
+  }
+
   fn block(&mut self, id: BlockId) {
     let ip = self.instructions.len();
     self.blocks.insert(id.to_uint(), ip);
@@ -191,13 +284,13 @@ impl GeneratorFunctions<Kind, Group, Register> for Emulator {
 
   fn instr(&mut self,
            kind: &Kind,
-           output: Option<Value<Group, Register>>,
+           outputs: &[Value<Group, Register>],
            inputs: &[Value<Group, Register>],
            temporary: &[Value<Group, Register>],
            succ: &[BlockId]) {
     self.instructions.push(Generic(GenericInstruction {
       kind: *kind,
-      output: output,
+      outputs: outputs.to_owned(),
       inputs: inputs.to_owned(),
       temporary: temporary.to_owned(),
       succ: succ.to_owned()
@@ -206,22 +299,34 @@ impl GeneratorFunctions<Kind, Group, Register> for Emulator {
 }
 
 pub fn run_test(expected: Either<uint, float>,
-                body: &fn(b: &mut Graph<Kind, Group, Register>)) {
+                body: &fn(b: &mut Graph<Kind, Group, Register>))
+    -> AllocatorResult<Register> {
   let mut g = ~Graph::new();
 
   body(&mut *g);
 
-  g.allocate().get();
+  match g.validate() {
+    Ok(_) => (),
+    Err(errors) => fail!(fmt!("validation failed: %?", errors))
+  }
+  return allocate_and_run(&mut *g, expected);
+}
+
+pub fn allocate_and_run(g: &mut Graph<Kind, Group, Register>,
+                        expected: Either<uint, float>)
+    -> AllocatorResult<Register> {
+  let res = g.allocate().get();
 
   let mut emu = Emulator::new();
-  let got = emu.run(g);
+  let got = emu.run(g, &res);
   if got != expected {
     fail!(fmt!("got %? expected %?", got, expected));
   }
+  return res;
 }
 
 impl Emulator {
-  fn new() -> Emulator {
+  pub fn new() -> Emulator {
     Emulator {
       ip: 0,
       result: None,
@@ -230,14 +335,24 @@ impl Emulator {
       registers: ~SmallIntMap::new(),
       double_registers: ~SmallIntMap::new(),
       stack: ~SmallIntMap::new(),
-      double_stack: ~SmallIntMap::new()
+      double_stack: ~SmallIntMap::new(),
+      rematerialized: 0,
+      stores: 0
     }
   }
 
-  fn run(&mut self,
-         graph: &Graph<Kind, Group, Register>) -> Either<uint, float> {
+  pub fn run(&mut self,
+             graph: &Graph<Kind, Group, Register>,
+             result: &AllocatorResult<Register>) -> Either<uint, float> {
     // Generate instructions
-    graph.generate(self);
+    graph.generate(result, self);
+
+    // Parameters are receiving 1, 2, ... in order of their creation
+    let mut arg = 1;
+    for (_, location) in graph.params.iter() {
+      self.put(location.clone(), Left(arg));
+      arg += 1;
+    }
 
     let instructions = self.instructions.clone();
     loop {
@@ -309,10 +424,25 @@ impl Emulator {
       },
       _ => fail!()
     };
+
+    // Writes are trashing values of aliased registers
+    match slot {
+      RegisterVal(r) => for alias in r.aliases().iter() {
+        match alias.group() {
+          Normal => self.registers.remove(&alias.to_uint()),
+          Double => self.double_registers.remove(&alias.to_uint())
+        };
+      },
+      _ => ()
+    }
   }
 
   fn exec_generic(&mut self, instr: &GenericInstruction) {
-    let out = instr.output;
+    let out = if instr.outputs.len() > 0 {
+      Some(instr.outputs[0])
+    } else {
+      None
+    };
     let inputs = instr.inputs.map(|i| self.get(*i));
     let tmp = instr.temporary.clone();
 
@@ -321,16 +451,82 @@ impl Emulator {
                             Left(inputs[0].unwrap_left() + 1)),
       JustUse => (), // nop
       FixedUse => (), // nop
+      StackUse => assert!(instr.inputs[0] == StackVal(Normal, StackId(0))),
+      StackPair => fail!("Both inputs can't be in the same slot"),
+      Invalid => fail!("Invalid instruction"),
       Nop => (), // nop
-      Print => self.put(out.expect("Print out"), Left(0)),
+      Print | IndirectPrint => {
+        // Address of callee is loaded before the call, and should not
+        // overwrite the argument
+        for t in tmp.iter() {
+          self.put(*t, Left(0));
+        }
+        assert!(self.get(instr.inputs[0]) == inputs[0]);
+
+        // Trash clobbered registers
+        let groups: ~[Group] = GroupHelper::groups();
+        for group in groups.iter() {
+          for r in instr.kind.clobbers(group).iter() {
+            match *group {
+              Normal => self.registers.remove(&r.to_uint()),
+              Double => self.double_registers.remove(&r.to_uint())
+            };
+          }
+        }
+        self.put(out.expect("Print out"), Left(0));
+      },
       Number(n) => self.put(out.expect("Number out"), Left(n)),
+      Constant(n) => self.put(out.expect("Constant out"), Left(n)),
+      StackArg(n) => {
+        // Caller has stored argument in the stack slot
+        assert!(out == Some(StackVal(Normal, StackId(n))));
+        self.put(out.expect("StackArg out"), Left(n));
+      },
       DoubleNumber(n) => self.put(out.expect("Double Number out"), Right(n)),
       Sum => self.put(out.expect("Sum out"),
                       Left(inputs[0].unwrap_left() + inputs[1].unwrap_left())),
+      MemSum => {
+        match instr.inputs[1] {
+          StackVal(_, _) => (),
+          _ => fail!("Memory operand expected")
+        }
+        self.put(out.expect("MemSum out"),
+                 Left(inputs[0].unwrap_left() + inputs[1].unwrap_left()));
+      },
       MultAdd => self.put(out.expect("Mult add out"),
                           Left(inputs[0].unwrap_left() *
                                  inputs[1].unwrap_left() +
                                inputs[2].unwrap_left())),
+      InplaceSum => {
+        assert!(instr.inputs[0] == instr.outputs[0]);
+        self.put(instr.outputs[0],
+                 Left(inputs[0].unwrap_left() + inputs[1].unwrap_left()));
+      },
+      Accumulate => {
+        // Output is written before reading second input
+        self.put(instr.outputs[0], inputs[0]);
+        let v = self.get(instr.inputs[1]).unwrap_left();
+        self.put(instr.outputs[0], Left(inputs[0].unwrap_left() + v));
+      },
+      EarlySum => {
+        // Output is written before reading any input
+        self.put(instr.outputs[0], Left(0));
+        let a = self.get(instr.inputs[0]).unwrap_left();
+        let b = self.get(instr.inputs[1]).unwrap_left();
+        self.put(instr.outputs[0], Left(a + b));
+      },
+      Cpuid => {
+        // Scratch registers are trashed
+        assert!(tmp == ~[RegisterVal(rcx), RegisterVal(rdx)]);
+        self.registers.remove(&rcx.to_uint());
+        self.registers.remove(&rdx.to_uint());
+        self.put(out.expect("Cpuid out"), Left(1));
+      },
+      DivMod => {
+        let (a, b) = (inputs[0].unwrap_left(), inputs[1].unwrap_left());
+        self.put(instr.outputs[0], Left(a / b));
+        self.put(instr.outputs[1], Left(a % b));
+      },
       DoubleSum => self.put(out.expect("Double sum out"),
                             Right(inputs[0].unwrap_right() +
                                   inputs[1].unwrap_right())),
@@ -356,6 +552,12 @@ impl Emulator {
                                 .expect("branch false");
         }
         return;
+      },
+      Switch => {
+        self.ip = *self.blocks.find(&instr.succ[inputs[0].unwrap_left()]
+                                              .to_uint())
+                              .expect("switch target");
+        return;
       }
     }
 
@@ -24,32 +24,32 @@ fn realword_example() {
       b.make_root();
 
       b.add_existing(ret);
-      let zero = b.add(Number(0), ~[]);
+      let zero = b.add(Number(0), ~[])[0];
       b.to_phi(zero, phi);
       b.goto(cond);
     };
 
     do g.with_block(cond) |b| {
-      let ten = b.add(Number(10), ~[]);
+      let ten = b.add(Number(10), ~[])[0];
       b.add(JustUse, ~[phi]);
       b.add(BranchIfBigger, ~[phi, ten]);
       b.branch(right, left);
     };
 
     do g.with_block(left) |b| {
-      let print_res = b.add(Print, ~[phi]);
+      let print_res = b.add(Print, ~[phi])[0];
       b.add(Increment, ~[print_res]);
       b.goto(after_left);
     };
 
     do g.with_block(after_left) |b| {
-      let counter = b.add(Increment, ~[phi]);
+      let counter = b.add(Increment, ~[phi])[0];
       b.to_phi(counter, phi);
       b.goto(cond);
     };
 
     do g.with_block(right) |b| {
-      let sum = b.add(Sum, ~[ret, phi]);
+      let sum = b.add(Sum, ~[ret, phi])[0];
       b.add(Return, ~[sum]);
       b.end();
     };
@@ -79,7 +79,7 @@ fn nested_loops() {
 
       // Pre
       let pre = do g.block() |b| {
-        let init = b.add(Number(0), ~[]);
+        let init = b.add(Number(0), ~[])[0];
         b.to_phi(init, phi);
         b.to_phi(inp, res_phi);
         b.goto(cond);
@@ -87,14 +87,14 @@ fn nested_loops() {
 
       // Cond
       do g.with_block(cond) |b| {
-        let limit = b.add(Number(4), ~[]);
+        let limit = b.add(Number(4), ~[])[0];
         b.add(BranchIfBigger, ~[phi, limit]);
         b.branch(after, body);
       };
 
       // Body
       do g.with_block(body) |b| {
-        let next = b.add(Increment, ~[phi]);
+        let next = b.add(Increment, ~[phi])[0];
         b.to_phi(next, phi);
       };
 
@@ -116,7 +116,7 @@ fn nested_loops() {
         // Just loop
         None => {
           do g.with_block(body) |b| {
-            let next = b.add(Increment, ~[res_phi]);
+            let next = b.add(Increment, ~[res_phi])[0];
             b.to_phi(next, res_phi);
             b.goto(cond);
           };
@@ -158,18 +158,18 @@ fn double_and_normal() {
       let mut doubles = ~[];
       let count = 16;
       for i in iterator::range(0, count) {
-        normals.push(b.add(Number(i + 1), ~[]));
-        doubles.push(b.add(DoubleNumber(((i + 1) as float) / 8f), ~[]));
+        normals.push(b.add(Number(i + 1), ~[])[0]);
+        doubles.push(b.add(DoubleNumber(((i + 1) as float) / 8f), ~[])[0]);
       }
 
-      let mut total = b.add(DoubleNumber(0f), ~[]);
+      let mut total = b.add(DoubleNumber(0f), ~[])[0];
       for i in iterator::range(count - 1, 0).reverse_() {
-        let left = b.add(Sum, ~[normals[i - 1], normals[i]]);
-        let right = b.add(DoubleSum, ~[doubles[i - 1], doubles[i]]);
-        let double_left = b.add(ToDouble, ~[left]);
+        let left = b.add(Sum, ~[normals[i - 1], normals[i]])[0];
+        let right = b.add(DoubleSum, ~[doubles[i - 1], doubles[i]])[0];
+        let double_left = b.add(ToDouble, ~[left])[0];
 
-        let subtotal = b.add(DoubleSum, ~[double_left, right]);
-        total = b.add(DoubleSum, ~[total, subtotal]);
+        let subtotal = b.add(DoubleSum, ~[double_left, right])[0];
+        total = b.add(DoubleSum, ~[total, subtotal])[0];
       }
       b.add(ReturnDouble, ~[total]);
       b.end();
@@ -183,10 +183,10 @@ fn parallel_move_cycles() {
     do g.block() |b| {
       b.make_root();
 
-      let n1 = b.add(Number(1), ~[]);
-      let n2 = b.add(Number(2), ~[]);
-      let n3 = b.add(Number(3), ~[]);
-      let n4 = b.add(Number(4), ~[]);
+      let n1 = b.add(Number(1), ~[])[0];
+      let n2 = b.add(Number(2), ~[])[0];
+      let n3 = b.add(Number(3), ~[])[0];
+      let n4 = b.add(Number(4), ~[])[0];
 
       // 1 <=> 2
       b.add(FixedUse, ~[n1, n2, n3, n4]);
@@ -208,15 +208,1251 @@ fn parallel_move_cycles() {
       b.add(FixedUse, ~[n1, n2, n3, n4]);
       b.add(FixedUse, ~[n3, n2, n4, n1]);
 
-      let ten = b.add(Number(10), ~[]);
-      let mut res = b.add(Number(0), ~[]);
-      res = b.add(MultAdd, ~[res, ten, n1]);
-      res = b.add(MultAdd, ~[res, ten, n2]);
-      res = b.add(MultAdd, ~[res, ten, n3]);
-      res = b.add(MultAdd, ~[res, ten, n4]);
+      let ten = b.add(Number(10), ~[])[0];
+      let mut res = b.add(Number(0), ~[])[0];
+      res = b.add(MultAdd, ~[res, ten, n1])[0];
+      res = b.add(MultAdd, ~[res, ten, n2])[0];
+      res = b.add(MultAdd, ~[res, ten, n3])[0];
+      res = b.add(MultAdd, ~[res, ten, n4])[0];
 
       b.add(Return, ~[res]);
       b.end();
     };
   };
 }
+
+#[test]
+fn switch_and_merge() {
+  do run_test(Left(42)) |g| {
+    let phi = g.phi(Normal);
+    let cases = ~[g.empty_block(), g.empty_block(), g.empty_block()];
+    let merge = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+      let index = b.add(Number(2), ~[])[0];
+      b.add(Switch, ~[index]);
+      b.switch(cases);
+    };
+
+    for (i, case) in cases.iter().enumerate() {
+      do g.with_block(*case) |b| {
+        let value = b.add(Number(i * 10 + 22), ~[])[0];
+        b.to_phi(value, phi);
+        b.goto(merge);
+      };
+    }
+
+    do g.with_block(merge) |b| {
+      b.add(Return, ~[phi]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn switch_duplicate_targets() {
+  do run_test(Left(5)) |g| {
+    let other = g.empty_block();
+    let join = g.empty_block();
+    let x = g.new_instr(Number(5), ~[]);
+    let y = g.new_instr(Number(7), ~[]);
+
+    // Both edges to `join` share the same phi input
+    let root = do g.block() |b| {
+      b.make_root();
+      b.add_existing(x);
+      b.add_existing(y);
+      let index = b.add(Number(2), ~[])[0];
+      b.add(Switch, ~[index]);
+      b.switch([join, other, join]);
+    };
+
+    do g.with_block(other) |b| {
+      b.add(Nop, ~[]);
+      b.goto(join);
+    };
+
+    let mut phi = None;
+    do g.with_block(join) |b| {
+      phi = Some(b.phi(Normal));
+      b.add(Return, ~[phi.unwrap()]);
+      b.end();
+    };
+
+    g.add_incoming(phi.unwrap(), root, x);
+    g.add_incoming(phi.unwrap(), other, y);
+  };
+}
+
+#[test]
+fn ssa_phis() {
+  do run_test(Left(55)) |g| {
+    let cond = g.empty_block();
+    let body = g.empty_block();
+    let exit = g.empty_block();
+    let zero = g.new_instr(Number(0), ~[]);
+    let one = g.new_instr(Number(1), ~[]);
+
+    let root = do g.block() |b| {
+      b.make_root();
+      b.add_existing(zero);
+      b.add_existing(one);
+      b.goto(cond);
+    };
+
+    // Counter and two last fibonacci numbers
+    let mut phis = ~[];
+    do g.with_block(cond) |b| {
+      for _ in iterator::range(0, 3) {
+        phis.push(b.phi(Normal));
+      }
+      let limit = b.add(Number(9), ~[])[0];
+      b.add(BranchIfBigger, ~[phis[0], limit]);
+      b.branch(exit, body);
+    };
+    let (counter, prev, cur) = (phis[0], phis[1], phis[2]);
+
+    let mut next = ~[];
+    do g.with_block(body) |b| {
+      next.push(b.add(Increment, ~[counter])[0]);
+      next.push(b.add(Sum, ~[prev, cur])[0]);
+      b.goto(cond);
+    };
+
+    g.add_incoming(counter, root, zero);
+    g.add_incoming(prev, root, zero);
+    g.add_incoming(cur, root, one);
+    g.add_incoming(counter, body, next[0]);
+    g.add_incoming(prev, body, cur);
+    g.add_incoming(cur, body, next[1]);
+
+    do g.with_block(exit) |b| {
+      b.add(Return, ~[prev]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn to_phi_swap() {
+  do run_test(Left(21)) |g| {
+    let left = g.phi(Normal);
+    let right = g.phi(Normal);
+    let counter = g.phi(Normal);
+    let cond = g.empty_block();
+    let body = g.empty_block();
+    let exit = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+      let one = b.add(Number(1), ~[])[0];
+      let two = b.add(Number(2), ~[])[0];
+      let zero = b.add(Number(0), ~[])[0];
+      b.to_phi(one, left);
+      b.to_phi(two, right);
+      b.to_phi(zero, counter);
+      b.goto(cond);
+    };
+
+    do g.with_block(cond) |b| {
+      let limit = b.add(Number(2), ~[])[0];
+      b.add(BranchIfBigger, ~[counter, limit]);
+      b.branch(exit, body);
+    };
+
+    // left, right = right, left
+    do g.with_block(body) |b| {
+      let next = b.add(Increment, ~[counter])[0];
+      b.to_phi(right, left);
+      b.to_phi(left, right);
+      b.to_phi(next, counter);
+      b.goto(cond);
+    };
+
+    do g.with_block(exit) |b| {
+      let ten = b.add(Number(10), ~[])[0];
+      let res = b.add(MultAdd, ~[left, ten, right])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn graph_errors() {
+  let mut g: Graph<Kind, Group, Register> = Graph::new();
+  let phi = g.phi(Normal);
+  let missing = g.empty_block();
+
+  let block = do g.block() |b| {
+    match b.try_end() {
+      Err(EmptyBlock(_)) => (),
+      _ => fail!("Expected EmptyBlock error")
+    }
+
+    let n = b.add(Number(1), ~[])[0];
+    match b.try_to_phi(n, n) {
+      Err(NotPhi(id)) if id == n => (),
+      _ => fail!("Expected NotPhi error")
+    }
+    match b.try_add_existing(n) {
+      Err(AlreadyAdded(id)) if id == n => (),
+      _ => fail!("Expected AlreadyAdded error")
+    }
+
+    let ret = b.add(Return, ~[n])[0];
+    match b.try_add(Return, ~[ret]) {
+      Err(NoOutput(id)) if id == ret => (),
+      _ => fail!("Expected NoOutput error")
+    }
+    b.to_phi(n, phi);
+    b.goto(missing);
+
+    match b.try_goto(missing) {
+      Err(AlreadyEnded(_)) => (),
+      _ => fail!("Expected AlreadyEnded error")
+    }
+  };
+
+  // Failed addition should not leave instruction in the graph
+  let count = g.instructions.len();
+  do g.with_block(block) |b| {
+    match b.try_add(Number(2), ~[]) {
+      Err(AlreadyEnded(_)) => (),
+      _ => fail!("Expected AlreadyEnded error")
+    }
+  };
+  assert!(g.instructions.len() == count);
+}
+
+#[test]
+fn validation_errors() {
+  let mut g: Graph<Kind, Group, Register> = Graph::new();
+  let left = g.empty_block();
+  let right = g.empty_block();
+  let value = g.new_instr(Number(2), ~[]);
+  let double = g.new_instr(DoubleNumber(2f), ~[]);
+
+  do g.block() |b| {
+    b.make_root();
+    let n = b.add(Number(1), ~[])[0];
+    b.add(BranchIfBigger, ~[n, n]);
+    b.branch(left, right);
+  };
+  do g.with_block(left) |b| {
+    b.add_existing(value);
+    b.add_existing(double);
+    b.add(Sum, ~[value]);
+    b.add(Increment, ~[double]);
+  };
+  do g.with_block(right) |b| {
+    b.add(Return, ~[value]);
+    b.end();
+  };
+
+  let errors = match g.validate() {
+    Ok(_) => fail!("Validation should fail"),
+    Err(errors) => errors
+  };
+  assert!(errors.len() == 4);
+  assert!(errors.contains(&NotEnded(left)));
+  assert!(errors.any(|e| match *e {
+    NotDominated(_, input) => input == value,
+    _ => false
+  }));
+  assert!(errors.any(|e| match *e {
+    WrongInputCount(_, 2, 1) => true,
+    _ => false
+  }));
+  assert!(errors.any(|e| match *e {
+    GroupMismatch(_, 0) => true,
+    _ => false
+  }));
+}
+
+#[test]
+fn same_as_validation() {
+  let mut g: Graph<Kind, Group, Register> = Graph::new();
+
+  do g.block() |b| {
+    b.make_root();
+    let res = b.add(InplaceSum, ~[])[0];
+    b.add(Return, ~[res]);
+    b.end();
+  };
+
+  let errors = match g.validate() {
+    Ok(_) => fail!("Validation should fail"),
+    Err(errors) => errors
+  };
+  assert!(errors.any(|e| match *e {
+    WrongSameAs(_, 0) => true,
+    _ => false
+  }));
+}
+
+#[test]
+fn use_kind_validation() {
+  let mut g: Graph<Kind, Group, Register> = Graph::new();
+
+  let mut invalid = None;
+  do g.block() |b| {
+    b.make_root();
+    let n = b.add(Number(1), ~[])[0];
+    invalid = Some(b.add(Invalid, ~[n])[0]);
+    b.add(Return, ~[n]);
+    b.end();
+  };
+  let invalid = invalid.unwrap();
+
+  // Inputs and temporaries can't be the same as input, and temporaries
+  // can't be in the stack
+  let errors = match g.validate() {
+    Ok(_) => fail!("Validation should fail"),
+    Err(errors) => errors
+  };
+  assert!(errors.any(|e| match *e {
+    WrongUseKind(id, 0) => id == invalid,
+    _ => false
+  }));
+  assert!(errors.any(|e| match *e {
+    WrongTemporary(id, 0) => id == invalid,
+    _ => false
+  }));
+  assert!(errors.any(|e| match *e {
+    WrongTemporary(id, 1) => id == invalid,
+    _ => false
+  }));
+}
+
+#[test]
+fn unreachable_blocks() {
+  let mut dead = None;
+
+  let res = do run_test(Left(3)) |g| {
+    let exit = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+      b.add(Nop, ~[]);
+      b.goto(exit);
+    };
+
+    // Dead block jumping into live one
+    dead = Some(do g.block() |b| {
+      let n = b.add(Number(4), ~[])[0];
+      b.add(Print, ~[n]);
+      b.goto(exit);
+    });
+
+    do g.with_block(exit) |b| {
+      let n = b.add(Number(3), ~[])[0];
+      b.add(Return, ~[n]);
+      b.end();
+    };
+  };
+
+  assert!(res.removed_blocks == ~[dead.unwrap()]);
+  assert!(res.removed_instructions.len() == 2);
+}
+
+#[test]
+fn unreachable_intervals() {
+  let mut g = ~Graph::new();
+  let exit = g.empty_block();
+
+  do g.block() |b| {
+    b.make_root();
+    b.add(Nop, ~[]);
+    b.goto(exit);
+  };
+
+  let mut dead = None;
+  do g.block() |b| {
+    let n = b.add(Number(4), ~[])[0];
+    dead = Some(n);
+    b.add(Print, ~[n]);
+    b.goto(exit);
+  };
+  let interval = g.get_output(&dead.unwrap());
+
+  do g.with_block(exit) |b| {
+    let n = b.add(Number(3), ~[])[0];
+    b.add(Return, ~[n]);
+    b.end();
+  };
+
+  // Intervals of removed instructions should be removed too
+  allocate_and_run(&mut *g, Left(3));
+  assert!(!g.intervals.contains_key(&interval.to_uint()));
+}
+
+#[test]
+fn unreachable_phi_input() {
+  do run_test(Left(3)) |g| {
+    let exit = g.empty_block();
+
+    let mut values = ~[];
+    let root = do g.block() |b| {
+      b.make_root();
+      values.push(b.add(Number(3), ~[])[0]);
+      b.goto(exit);
+    };
+    let dead = do g.block() |b| {
+      values.push(b.add(Number(4), ~[])[0]);
+      b.goto(exit);
+    };
+
+    let mut phi = None;
+    do g.with_block(exit) |b| {
+      phi = Some(b.phi(Normal));
+      b.add(Return, ~[phi.unwrap()]);
+      b.end();
+    };
+
+    // Phi is hinted to use location of the removed value
+    g.add_incoming(phi.unwrap(), dead, values[1]);
+    g.add_incoming(phi.unwrap(), root, values[0]);
+  };
+}
+
+#[test]
+fn cross_edges() {
+  do run_test(Left(4)) |g| {
+    let phi = g.phi(Normal);
+    let a = g.empty_block();
+    let skip = g.empty_block();
+    let cond = g.empty_block();
+    let body = g.empty_block();
+    let exit = g.empty_block();
+
+    let root = do g.block() |b| {
+      b.make_root();
+      let one = b.add(Number(1), ~[])[0];
+      let zero = b.add(Number(0), ~[])[0];
+      b.add(BranchIfBigger, ~[zero, one]);
+      b.branch(a, skip);
+    };
+
+    // Cross edge, not a loop
+    do g.with_block(skip) |b| {
+      b.add(Nop, ~[]);
+      b.goto(a);
+    };
+
+    do g.with_block(a) |b| {
+      let zero = b.add(Number(0), ~[])[0];
+      b.to_phi(zero, phi);
+      b.goto(cond);
+    };
+
+    do g.with_block(cond) |b| {
+      let limit = b.add(Number(3), ~[])[0];
+      b.add(BranchIfBigger, ~[phi, limit]);
+      b.branch(exit, body);
+    };
+
+    do g.with_block(body) |b| {
+      let next = b.add(Increment, ~[phi])[0];
+      b.to_phi(next, phi);
+      b.goto(cond);
+    };
+
+    do g.with_block(exit) |b| {
+      b.add(Return, ~[phi]);
+      b.end();
+    };
+
+    let dom = g.dominators();
+    assert!(dom.idom(&a) == Some(root));
+    assert!(dom.idom(&cond) == Some(a));
+    assert!(dom.dominates(&root, &body));
+
+    let loops = g.loops();
+    assert!(loops.len() == 1);
+    assert!(loops[0].header == cond);
+    assert!(loops[0].ends == ~[body]);
+    assert!(loops[0].blocks.len() == 2);
+    assert!(loops[0].depth == 1);
+  };
+}
+
+#[test]
+fn irreducible_loop() {
+  do run_test(Left(6)) |g| {
+    let phi = g.phi(Normal);
+    let pre = g.empty_block();
+    let a = g.empty_block();
+    let body = g.empty_block();
+    let exit = g.empty_block();
+
+    do g.block() |b| {
+      b.make_root();
+      let zero = b.add(Number(0), ~[])[0];
+      b.to_phi(zero, phi);
+      b.goto(pre);
+    };
+
+    // Enter the loop through both `a` and `body`
+    do g.with_block(pre) |b| {
+      let one = b.add(Number(1), ~[])[0];
+      b.add(BranchIfBigger, ~[phi, one]);
+      b.branch(a, body);
+    };
+
+    do g.with_block(a) |b| {
+      let limit = b.add(Number(5), ~[])[0];
+      b.add(BranchIfBigger, ~[phi, limit]);
+      b.branch(exit, body);
+    };
+
+    do g.with_block(body) |b| {
+      let next = b.add(Increment, ~[phi])[0];
+      b.to_phi(next, phi);
+      b.goto(a);
+    };
+
+    do g.with_block(exit) |b| {
+      b.add(Return, ~[phi]);
+      b.end();
+    };
+
+    let loops = g.loops();
+    assert!(loops.len() == 1);
+    assert!(loops[0].header == a);
+    assert!(!loops[0].reducible);
+  };
+}
+
+#[test]
+fn critical_edges() {
+  do run_test(Left(2)) |g| {
+    let other = g.empty_block();
+    let join = g.empty_block();
+    let x = g.new_instr(Number(1), ~[]);
+    let y = g.new_instr(Number(2), ~[]);
+
+    // Edge from root to join is critical
+    let root = do g.block() |b| {
+      b.make_root();
+      b.add_existing(x);
+      b.add_existing(y);
+      b.add(BranchIfBigger, ~[y, x]);
+      b.branch(other, join);
+    };
+
+    do g.with_block(other) |b| {
+      b.add(Nop, ~[]);
+      b.goto(join);
+    };
+
+    let mut phi = None;
+    do g.with_block(join) |b| {
+      phi = Some(b.phi(Normal));
+      b.add(Return, ~[phi.unwrap()]);
+      b.end();
+    };
+
+    g.add_incoming(phi.unwrap(), root, x);
+    g.add_incoming(phi.unwrap(), other, y);
+  };
+}
+
+#[test]
+fn reset_allocation() {
+  let mut g: ~Graph<Kind, Group, Register> = ~Graph::new();
+  let phi = g.phi(Normal);
+  let cond = g.empty_block();
+  let body = g.empty_block();
+  let exit = g.empty_block();
+
+  do g.block() |b| {
+    b.make_root();
+    let zero = b.add(Number(0), ~[])[0];
+    b.to_phi(zero, phi);
+    b.goto(cond);
+  };
+
+  do g.with_block(cond) |b| {
+    let limit = b.add(Number(10), ~[])[0];
+    b.add(BranchIfBigger, ~[phi, limit]);
+    b.branch(exit, body);
+  };
+
+  do g.with_block(body) |b| {
+    let next = b.add(Increment, ~[phi])[0];
+    b.to_phi(next, phi);
+    b.goto(cond);
+  };
+
+  do g.with_block(exit) |b| {
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+
+  let first = allocate_and_run(&mut *g, Left(11));
+
+  // Allocate the same graph once again
+  g.reset_allocation();
+  let second = allocate_and_run(&mut *g, Left(11));
+  assert!(first.spill_count == second.spill_count);
+}
+
+#[test]
+fn edit_after_allocation() {
+  let mut g = ~Graph::new();
+
+  let mut ids = ~[];
+  do g.block() |b| {
+    b.make_root();
+    let one = b.add(Number(1), ~[])[0];
+    let two = b.add(Number(2), ~[])[0];
+    let sum = b.add(Sum, ~[one, two])[0];
+    ids.push(one);
+    ids.push(sum);
+    ids.push(b.add(Return, ~[sum])[0]);
+    b.end();
+  };
+  let res = allocate_and_run(&mut *g, Left(3));
+
+  // Instructions were renumbered by the allocation
+  g.reset_allocation();
+  let ids = do ids.map() |id| {
+    *res.renamed_instructions.find(&id.to_uint()).expect("renamed")
+  };
+  let (one, sum, ret) = (ids[0], ids[1], ids[2]);
+
+  // Return `one + two + one` instead
+  let extra = g.new_instr(Sum, ~[sum, one]);
+  g.insert_before(ret, extra);
+  g.replace_all_uses(sum, extra);
+
+  assert!(g.validate().is_ok());
+  allocate_and_run(&mut *g, Left(4));
+}
+
+#[test]
+fn graph_mutation() {
+  do run_test(Left(6)) |g| {
+    let exit = g.empty_block();
+
+    let mut instrs = ~[];
+    let root = do g.block() |b| {
+      b.make_root();
+      instrs.push(b.add(Number(1), ~[])[0]);
+      instrs.push(b.add(Number(2), ~[])[0]);
+      instrs.push(b.add(Number(100), ~[])[0]);
+      b.goto(exit);
+    };
+    let (one, two, dead) = (instrs[0], instrs[1], instrs[2]);
+
+    let mut instrs = ~[];
+    do g.with_block(exit) |b| {
+      let sum = b.add(Sum, ~[one, two])[0];
+      instrs.push(sum);
+      instrs.push(b.add(Return, ~[sum])[0]);
+      b.end();
+    };
+    let (sum, ret) = (instrs[0], instrs[1]);
+
+    // Dead code removal
+    match g.try_remove_instr(one) {
+      Err(StillUsed(id, user)) if id == one && user == sum => (),
+      _ => fail!("Expected StillUsed error")
+    }
+    g.remove_instr(dead);
+
+    // Constant folding
+    let five = g.new_instr(Number(5), ~[]);
+    g.insert_after(two, five);
+    g.replace_all_uses(two, five);
+    g.remove_instr(two);
+
+    // Reordering
+    let nop = g.new_instr(Nop, ~[]);
+    match g.try_insert_after(ret, nop) {
+      Err(AlreadyEnded(id)) if id == exit => (),
+      _ => fail!("Expected AlreadyEnded error")
+    }
+    g.insert_before(ret, nop);
+    g.move_instr_to_block(sum, root);
+  };
+}
+
+#[test]
+fn move_errors() {
+  let mut g: Graph<Kind, Group, Register> = Graph::new();
+  let exit = g.empty_block();
+
+  let mut instrs = ~[];
+  let root = do g.block() |b| {
+    b.make_root();
+    instrs.push(b.add(Number(1), ~[])[0]);
+    b.goto(exit);
+  };
+  do g.with_block(exit) |b| {
+    let phi = b.phi(Normal);
+    instrs.push(phi);
+    b.add(Return, ~[phi]);
+    b.end();
+  };
+  let (n, phi) = (instrs[0], instrs[1]);
+  g.add_incoming(phi, root, n);
+
+  match g.try_move_instr_to_block(phi, root) {
+    Err(NotMovable(id)) if id == phi => (),
+    _ => fail!("Expected NotMovable error")
+  }
+  match g.try_move_instr_to_block(n, exit) {
+    Err(EmptyBlock(id)) if id == root => (),
+    _ => fail!("Expected EmptyBlock error")
+  }
+}
+
+#[test]
+fn multiple_outputs() {
+  do run_test(Left(32)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let a = b.add(Number(17), ~[])[0];
+      let d = b.add(Number(5), ~[])[0];
+      let res = b.add(DivMod, ~[a, d]);
+      assert!(res.len() == 2);
+
+      let ten = b.add(Number(10), ~[])[0];
+      let sum = b.add(MultAdd, ~[res[0], ten, res[1]])[0];
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn multiple_outputs_mutation() {
+  do run_test(Left(32)) |g| {
+    let middle = g.empty_block();
+    let exit = g.empty_block();
+
+    let mut instrs = ~[];
+    do g.block() |b| {
+      b.make_root();
+      let a = b.add(Number(17), ~[])[0];
+      let d = b.add(Number(5), ~[])[0];
+      instrs.push_all(b.add(DivMod, ~[a, d]));
+      instrs.push(b.add(DivMod, ~[a, d])[0]);
+      b.goto(middle);
+    };
+    let (res, dead) = (~[instrs[0], instrs[1]], instrs[2]);
+
+    do g.with_block(middle) |b| {
+      b.add(Nop, ~[]);
+      b.goto(exit);
+    };
+
+    do g.with_block(exit) |b| {
+      let ten = b.add(Number(10), ~[])[0];
+      let sum = b.add(MultAdd, ~[res[0], ten, res[1]])[0];
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+
+    // Uses of projections are uses of instruction
+    match g.try_remove_instr(res[0]) {
+      Err(StillUsed(id, _)) if id == res[0] => (),
+      _ => fail!("Expected StillUsed error")
+    }
+
+    // Projections can't be separated from their instruction
+    match g.try_remove_instr(res[1]) {
+      Err(IsProjection(id)) if id == res[1] => (),
+      _ => fail!("Expected IsProjection error")
+    }
+    let nop = g.new_instr(Nop, ~[]);
+    match g.try_insert_before(res[1], nop) {
+      Err(IsProjection(id)) if id == res[1] => (),
+      _ => fail!("Expected IsProjection error")
+    }
+    g.insert_after(res[0], nop);
+    g.remove_instr(dead);
+
+    // Projections should follow their instruction
+    g.move_instr_to_block(res[0], middle);
+  };
+}
+
+#[test]
+fn two_address() {
+  do run_test(Left(23)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let a = b.add(Number(3), ~[])[0];
+      let c = b.add(Number(4), ~[])[0];
+
+      // `a` is live after instruction and should be copied
+      let first = b.add(InplaceSum, ~[a, c])[0];
+      // `first` is dead after it
+      let second = b.add(InplaceSum, ~[first, a])[0];
+      let third = b.add(InplaceSum, ~[second, second])[0];
+      let res = b.add(Sum, ~[third, a])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn operand_timing() {
+  do run_test(Left(29)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let a = b.add(Number(3), ~[])[0];
+      let c = b.add(Number(4), ~[])[0];
+
+      // Second input is dead after instruction, but is read after output
+      let sum = b.add(Accumulate, ~[a, c])[0];
+      let d = b.add(Number(5), ~[])[0];
+
+      // Output is written before both inputs are read
+      let early = b.add(EarlySum, ~[sum, d])[0];
+      let twice = b.add(EarlySum, ~[early, early])[0];
+      let res = b.add(Accumulate, ~[twice, d])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn register_aliases() {
+  do run_test(Right(20.0)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Every register of both groups is needed here, but rdx and xmm4
+      // can't be used at the same time
+      let n1 = b.add(Number(1), ~[])[0];
+      let n2 = b.add(Number(2), ~[])[0];
+      let n3 = b.add(Number(3), ~[])[0];
+      let n4 = b.add(Number(4), ~[])[0];
+      let d1 = b.add(DoubleNumber(1.0), ~[])[0];
+      let d2 = b.add(DoubleNumber(2.0), ~[])[0];
+      let d3 = b.add(DoubleNumber(3.0), ~[])[0];
+      let d4 = b.add(DoubleNumber(4.0), ~[])[0];
+
+      let mut n = b.add(Sum, ~[n1, n2])[0];
+      n = b.add(Sum, ~[n, n3])[0];
+      n = b.add(Sum, ~[n, n4])[0];
+      let mut d = b.add(DoubleSum, ~[d1, d2])[0];
+      d = b.add(DoubleSum, ~[d, d3])[0];
+      d = b.add(DoubleSum, ~[d, d4])[0];
+      let nd = b.add(ToDouble, ~[n])[0];
+      let res = b.add(DoubleSum, ~[nd, d])[0];
+      b.add(ReturnDouble, ~[res]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn callee_saved_registers() {
+  let res = do run_test(Left(6)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(3), ~[])[0];
+      let first = b.add(Number(4), ~[])[0];
+      b.add(Print, ~[first]);
+      let second = b.add(Number(5), ~[])[0];
+      b.add(Print, ~[second]);
+      let res = b.add(Sum, ~[n, n])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+
+  // `n` should live in rbx across calls
+  assert!(res.spill_count[0] == 0);
+  assert!(res.used_registers[Normal.to_uint()].contains(&rbx));
+  assert!(res.used_registers[Double.to_uint()].len() == 0);
+}
+
+#[test]
+fn caller_saved_registers() {
+  let res = do run_test(Left(1)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // `a` doesn't live across call, and should not occupy rbx
+      let a = b.add(Number(3), ~[])[0];
+      let sum = b.add(Sum, ~[a, a])[0];
+      let p = b.add(Print, ~[sum])[0];
+      let n = b.add(Number(1), ~[])[0];
+      let res = b.add(Sum, ~[p, n])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+
+  assert!(!res.used_registers[Normal.to_uint()].contains(&rbx));
+}
+
+#[test]
+fn fixed_temporary() {
+  do run_test(Left(7)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n1 = b.add(Number(1), ~[])[0];
+      let n2 = b.add(Number(2), ~[])[0];
+      let n3 = b.add(Number(3), ~[])[0];
+
+      // Values living across instruction should leave rcx and rdx
+      let id = b.add(Cpuid, ~[])[0];
+      let mut sum = b.add(Sum, ~[n1, n2])[0];
+      sum = b.add(Sum, ~[sum, n3])[0];
+      sum = b.add(Sum, ~[sum, id])[0];
+      b.add(Return, ~[sum]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn call_temporary() {
+  do run_test(Left(6)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(3), ~[])[0];
+      let arg = b.add(Number(4), ~[])[0];
+      b.add(IndirectPrint, ~[arg]);
+      let res = b.add(Sum, ~[n, n])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn stack_uses() {
+  let res = do run_test(Left(9)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Argument is passed in the stack slot 1
+      let arg = b.add(StackArg(1), ~[])[0];
+      let n2 = b.add(Number(2), ~[])[0];
+      let n3 = b.add(Number(3), ~[])[0];
+      let n4 = b.add(Number(4), ~[])[0];
+
+      // One of numbers should be spilled, but not into argument's slot
+      b.add(Print, ~[n2]);
+      let first = b.add(MemSum, ~[n3, arg])[0];
+      let second = b.add(MemSum, ~[n4, arg])[0];
+
+      // Value should be moved into the stack slot 0
+      b.add(StackUse, ~[first]);
+      let res = b.add(Sum, ~[first, second])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+
+  // Spill slot follows slots of the argument and the stack use
+  assert!(res.spill_count[0] >= 3);
+}
+
+#[test]
+fn fixed_stack_reuse() {
+  do run_test(Left(3)) |g| {
+    do g.block() |b| {
+      b.make_root();
+
+      // Both values are passed in the stack slot 0, one after another
+      let first = b.add(Number(1), ~[])[0];
+      let second = b.add(Number(2), ~[])[0];
+      b.add(StackUse, ~[first]);
+      b.add(StackUse, ~[second]);
+      let res = b.add(Sum, ~[first, second])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+}
+
+#[test]
+fn fixed_stack_conflict() {
+  let mut g = ~Graph::new();
+  do g.block() |b| {
+    b.make_root();
+
+    // Both inputs should be in the stack slot 0 at the same time
+    let first = b.add(Number(1), ~[])[0];
+    let second = b.add(Number(2), ~[])[0];
+    b.add(StackPair, ~[first, second]);
+    b.add(Return, ~[first]);
+    b.end();
+  };
+
+  assert!(g.validate().is_ok());
+  assert!(g.allocate().is_err());
+}
+
+#[test]
+fn params() {
+  let mut g = ~Graph::new();
+  let entry = g.empty_block();
+  g.set_root(entry);
+
+  // Receive 1, 2 and 3
+  let a = g.param(RegisterVal(rcx));
+  let b = g.param(StackVal(Normal, StackId(2)));
+  let c = g.param(RegisterVal(rdx));
+
+  do g.with_block(entry) |bb| {
+    // `a` should be moved to rdx, and `c` out of it
+    bb.add(Print, ~[a]);
+    let sum = bb.add(Sum, ~[b, c])[0];
+    let res = bb.add(Sum, ~[sum, a])[0];
+    bb.add(Return, ~[res]);
+    bb.end();
+  };
+
+  assert!(g.validate().is_ok());
+  allocate_and_run(&mut *g, Left(6));
+}
+
+#[test]
+fn params_before_root() {
+  let mut g = ~Graph::new();
+
+  // Parameters might be created before the root block
+  let a = g.param(RegisterVal(rcx));
+  match g.try_param(RegisterVal(rcx)) {
+    Err(LocationTaken(id)) if id == a => (),
+    _ => fail!("Expected LocationTaken error")
+  }
+  let unused = g.param(StackVal(Normal, StackId(1)));
+  let b = g.param(RegisterVal(rdx));
+  g.remove_instr(unused);
+  assert!(g.params.len() == 2);
+
+  do g.block() |bb| {
+    bb.make_root();
+    let sum = bb.add(Sum, ~[a, b])[0];
+    bb.add(Return, ~[sum]);
+    bb.end();
+  };
+
+  assert!(g.validate().is_ok());
+  allocate_and_run(&mut *g, Left(3));
+}
+
+#[test]
+fn rematerialization() {
+  let mut g = ~Graph::new();
+
+  do g.block() |b| {
+    b.make_root();
+    let one = b.add(Number(1), ~[])[0];
+    let three = b.add(Number(3), ~[])[0];
+    let four = b.add(Number(4), ~[])[0];
+
+    // Only rbx survives the call, the other number should be recomputed
+    b.add(Print, ~[one]);
+    let res = b.add(Sum, ~[three, four])[0];
+    b.add(Return, ~[res]);
+    b.end();
+  };
+
+  assert!(g.validate().is_ok());
+  let res = g.allocate().get();
+
+  let mut emu = Emulator::new();
+  assert!(emu.run(&*g, &res) == Left(7));
+  assert!(emu.rematerialized > 0);
+
+  // Recomputed value doesn't need a spill slot
+  assert!(emu.stores == 0);
+  assert!(res.spill_count[0] == 0);
+}
+
+#[test]
+fn rematerialization_spilled_def() {
+  let res = do run_test(Left(15)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(1), ~[])[0];
+      let mut values = ~[];
+      for _ in iterator::range(0, 5) {
+        values.push(b.add(Increment, ~[n])[0]);
+      }
+
+      // Every register is busy, constant is written right into the stack
+      // while one of the values is spilled too
+      let k = b.add(Constant(5), ~[])[0];
+      let mut res = values[0];
+      for v in values.slice_from(1).iter() {
+        res = b.add(Sum, ~[res, *v])[0];
+      }
+      res = b.add(Sum, ~[res, k])[0];
+      b.add(Return, ~[res]);
+      b.end();
+    };
+  };
+
+  // Slot of the constant can't be shared with the spilled value
+  assert!(res.spill_count[0] >= 2);
+}
+
+#[test]
+fn spill_slot_sharing() {
+  let res = do run_test(Left(10)) |g| {
+    do g.block() |b| {
+      b.make_root();
+      let n = b.add(Number(1), ~[])[0];
+
+      // Values spilled around different calls should share the same slot
+      let x1 = b.add(Increment, ~[n])[0];
+      let y1 = b.add(Increment, ~[n])[0];
+      b.add(Print, ~[n]);
+      let s1 = b.add(Sum, ~[x1, y1])[0];
+
+      let x2 = b.add(Increment, ~[s1])[0];
+      let y2 = b.add(Increment, ~[s1])[0];
+      b.add(Print, ~[s1]);
+      let s2 = b.add(Sum, ~[x2, y2])[0];
+      b.add(Return, ~[s2]);
+      b.end();
+    };
+  };
+
+  assert!(res.spill_count[0] == 1);
+}
+
+#[test]
+fn spill_slot_packing() {
+  let mut g = ~Graph::new();
+
+  do g.block() |b| {
+    b.make_root();
+    let n = b.add(Number(1), ~[])[0];
+    let x = b.add(Increment, ~[n])[0];
+    let y = b.add(Increment, ~[n])[0];
+    let z = b.add(Increment, ~[n])[0];
+
+    // Every print reloads one value and spills it again, children of the
+    // same value are spilled at different times
+    b.add(Print, ~[x]);
+    b.add(Print, ~[y]);
+    b.add(Print, ~[z]);
+    b.add(Print, ~[x]);
+    b.add(Print, ~[y]);
+    b.add(Print, ~[z]);
+    let mut res = b.add(Sum, ~[x, y])[0];
+    res = b.add(Sum, ~[res, z])[0];
+    b.add(Return, ~[res]);
+    b.end();
+  };
+
+  assert!(g.validate().is_ok());
+  let res = allocate_and_run(&mut *g, Left(6));
+
+  // Walk gives every spilled child its own slot
+  let mut spilled = 0;
+  for (_, interval) in g.intervals.iter() {
+    match interval.value {
+      StackVal(_, _) if interval.ranges.len() > 0 => spilled += 1,
+      _ => ()
+    }
+  }
+  assert!(res.spill_count[0] < spilled);
+
+  // At most three values are live at once
+  assert!(res.spill_count[0] <= 3);
+}
+
+#[test]
+fn shared_spill_slot() {
+  let mut g = ~Graph::new();
+
+  do g.block() |b| {
+    b.make_root();
+    let n = b.add(Number(1), ~[])[0];
+    let x = b.add(Increment, ~[n])[0];
+    let y = b.add(Increment, ~[n])[0];
+
+    // One of values is spilled around both calls, and reloaded between them
+    b.add(Print, ~[n]);
+    let first = b.add(Sum, ~[x, y])[0];
+    b.add(Print, ~[first]);
+    let second = b.add(Sum, ~[x, y])[0];
+    b.add(Return, ~[second]);
+    b.end();
+  };
+
+  assert!(g.validate().is_ok());
+  let res = g.allocate().get();
+  let mut separate = Emulator::new();
+  assert!(separate.run(&*g, &res) == Left(4));
+
+  // Second spill should reuse the value stored by the first one
+  g.reset_allocation();
+  let res = g.allocate_with(AllocatorConfig { shared_spill_slot: true }).get();
+  let mut shared = Emulator::new();
+  assert!(shared.run(&*g, &res) == Left(4));
+  assert!(shared.stores < separate.stores);
+}
+
+#[test]
+fn shared_spill_slot_phis() {
+  let mut g = ~Graph::new();
+  let cond = g.empty_block();
+  let body = g.empty_block();
+  let exit = g.empty_block();
+  let zero = g.new_instr(Number(0), ~[]);
+  let one = g.new_instr(Number(1), ~[]);
+
+  let root = do g.block() |b| {
+    b.make_root();
+    b.add_existing(zero);
+    b.add_existing(one);
+    b.goto(cond);
+  };
+
+  // Phis are written on every iteration, their slots can't be trusted
+  let mut phis = ~[];
+  do g.with_block(cond) |b| {
+    for _ in iterator::range(0, 3) {
+      phis.push(b.phi(Normal));
+    }
+    let limit = b.add(Number(9), ~[])[0];
+    b.add(BranchIfBigger, ~[phis[0], limit]);
+    b.branch(exit, body);
+  };
+  let (counter, prev, cur) = (phis[0], phis[1], phis[2]);
+
+  let mut next = ~[];
+  do g.with_block(body) |b| {
+    b.add(Print, ~[counter]);
+    next.push(b.add(Increment, ~[counter])[0]);
+    next.push(b.add(Sum, ~[prev, cur])[0]);
+    b.goto(cond);
+  };
+
+  g.add_incoming(counter, root, zero);
+  g.add_incoming(prev, root, zero);
+  g.add_incoming(cur, root, one);
+  g.add_incoming(counter, body, next[0]);
+  g.add_incoming(prev, body, cur);
+  g.add_incoming(cur, body, next[1]);
+
+  do g.with_block(exit) |b| {
+    b.add(Return, ~[prev]);
+    b.end();
+  };
+
+  assert!(g.validate().is_ok());
+  let res = g.allocate_with(AllocatorConfig { shared_spill_slot: true }).get();
+  let mut emu = Emulator::new();
+  assert!(emu.run(&*g, &res) == Left(55));
+}
+